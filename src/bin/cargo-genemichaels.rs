@@ -0,0 +1,101 @@
+mod common;
+
+use clap::Parser;
+use common::{
+    process_packages,
+    FilesArgs,
+    FormatArgs,
+    PackageInfo,
+    Result,
+    Workspace,
+};
+use genemichaels::print_error_text;
+use std::{
+    env,
+    path::PathBuf,
+    process,
+};
+
+// Cargo runs `cargo-genemichaels genemichaels ARGS` for `cargo genemichaels ARGS`
+#[derive(Parser)]
+#[command(bin_name = "cargo")]
+enum Cargo {
+    /// Formats the current package, or with --all every package in the workspace
+    Genemichaels(Args),
+}
+
+#[derive(clap::Args)]
+struct Args {
+    #[arg(long, help = "Don't write anything, exit with an error if any files aren't formatted")]
+    check: bool,
+    #[arg(short, long, value_name = "NAME", help = "Package to format (can be repeated)")]
+    package: Vec<String>,
+    #[arg(long, conflicts_with = "package", help = "Format all packages in the workspace")]
+    all: bool,
+    #[command(flatten)]
+    files_args: FilesArgs,
+    #[command(flatten)]
+    format: FormatArgs,
+}
+
+fn main() {
+    let Cargo::Genemichaels(mut args) = Cargo::parse();
+    let config = args.format.config();
+
+    // Like cargo, prefer the manifest of the package being built when run by cargo
+    if args.files_args.manifest_path.is_none() {
+        if let Some(dir) = env::var_os("CARGO_MANIFEST_DIR") {
+            args.files_args.manifest_path = Some(PathBuf::from(dir).join(common::CARGO_TOML));
+        }
+    }
+    let res = args.files_args.output(args.check).and_then(|output| {
+        let excludes = args.files_args.excludes()?;
+        let (manifest_path, packages) = select_packages(&args)?;
+        let failed = process_packages(&args.files_args, &manifest_path, &packages, &excludes, config, output.clone());
+        output.finish(args.format.quiet, failed)?;
+        Ok(failed)
+    });
+    match res {
+        Ok(failed) => {
+            if failed {
+                process::exit(1);
+            }
+        },
+        Err(e) => {
+            print_error_text();
+            eprintln!("formatting: {:?}", e);
+            process::exit(1);
+        },
+    }
+}
+
+/// Which packages to format, picked like `cargo fmt` does.
+enum PackageSelection<'a> {
+    /// The package at the manifest, or all workspace members if it's a virtual manifest
+    Current,
+    /// Every package in the workspace containing the manifest
+    Workspace,
+    /// The workspace members with these names
+    Named(&'a [String]),
+}
+
+/// Finds the packages to format, returning the manifest used along with them.
+fn select_packages(args: &Args) -> Result<(PathBuf, Vec<PackageInfo>)> {
+    let selection = if args.all {
+        PackageSelection::Workspace
+    } else if !args.package.is_empty() {
+        PackageSelection::Named(&args.package)
+    } else {
+        PackageSelection::Current
+    };
+    let manifest_path = args.files_args.manifest_path.as_deref();
+    match selection {
+        PackageSelection::Current => {
+            let workspace = Workspace::load(manifest_path, false)?;
+            let is_virtual = workspace.manifest.package.is_none();
+            Ok(workspace.at_manifest(is_virtual))
+        },
+        PackageSelection::Workspace => Ok(Workspace::load(manifest_path, true)?.at_manifest(true)),
+        PackageSelection::Named(names) => Workspace::load(manifest_path, true)?.named(names),
+    }
+}
@@ -0,0 +1,1353 @@
+use anyhow::anyhow;
+use cargo_manifest::Manifest;
+use genemichaels::{
+    format_md_code_blocks,
+    format_str,
+    print_error_text,
+    print_skipping_text,
+    print_warning_text,
+    Comment,
+    CommentStyle,
+    FormatConfig,
+};
+use std::{
+    cmp::Reverse,
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    env::{
+        self,
+        current_dir,
+    },
+    ffi::OsStr,
+    fmt::Display,
+    fs,
+    panic::{
+        self,
+        AssertUnwindSafe,
+    },
+    path::{
+        Component,
+        Path,
+        PathBuf,
+    },
+    process::{
+        self,
+        Stdio,
+    },
+    result,
+    str::FromStr,
+    sync::{
+        mpsc,
+        Arc,
+        Mutex,
+    },
+    time,
+};
+use ignore::{
+    gitignore::{
+        Gitignore,
+        GitignoreBuilder,
+    },
+    WalkBuilder,
+};
+use serde::Deserialize;
+use sha2::{
+    Digest,
+    Sha256,
+};
+use syn::{
+    ext::IdentExt,
+    Attribute,
+    File,
+    Item,
+    Lit,
+    Meta,
+    NestedMeta,
+};
+
+pub type Result<T> = result::Result<T, anyhow::Error>;
+pub const CARGO_TOML: &str = "Cargo.toml";
+const CONFIG_FILE: &str = ".genemichaels.toml";
+pub const IGNORE_FILE: &str = ".genemichaelsignore";
+
+// Default size above which files are formatted item by item, to save memory
+const ITEM_BY_ITEM_THRESHOLD: usize = 256 * 1024;
+
+#[derive(Clone)]
+pub enum Offable<T> {
+    Off,
+    On(T),
+}
+
+impl<T: Display> std::fmt::Display for Offable<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Offable::Off => "off".fmt(f),
+            Offable::On(x) => x.fmt(f),
+        }
+    }
+}
+
+impl<E: std::error::Error + Send + Sync + 'static, T: FromStr<Err = E> + Clone + Display> FromStr for Offable<T> {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        if s == "off" {
+            Ok(Self::Off)
+        } else {
+            Ok(Self::On(T::from_str(s)?))
+        }
+    }
+}
+#[derive(Clone)]
+pub struct On;
+
+impl std::fmt::Display for On {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        "on".fmt(f)
+    }
+}
+
+#[derive(Debug)]
+pub struct OnErr(String);
+
+impl Display for OnErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for OnErr {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+impl FromStr for On {
+    type Err = OnErr;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        if s == "on" {
+            Ok(On)
+        } else {
+            Err(OnErr(format!("[{}] not allowed, must be on or off", s)))
+        }
+    }
+}
+
+/// Formatting options shared by the command line tools.
+#[derive(clap::Args, Clone)]
+pub struct FormatArgs {
+    #[arg(short, long, global = true, help = "Won't emit any output")]
+    pub quiet: bool,
+    #[arg(short, long, global = true, default_value_t = FormatConfig::default().max_width)]
+    line_length: usize,
+    #[arg(long, global = true, help = "For any node that's split, all parent nodes must also be split")]
+    root_splits: bool,
+    #[arg(
+        long,
+        global = true,
+        help = "Always split {} groups with >= this number of children; disable with `off`",
+        default_value_t = match FormatConfig::default().split_brace_threshold {
+            Some(x) => Offable::On(x),
+            None => Offable::Off,
+        },
+    )]
+    split_brace_threshold: Offable<usize>,
+    #[arg(
+        long,
+        global = true,
+        help = "Always split #[] attributes; disable with `off`",
+        default_value_t = match FormatConfig::default().split_attributes {
+            true => Offable::On(On),
+            false => Offable::Off,
+        },
+    )]
+    split_attributes: Offable<On>,
+    #[arg(
+        long,
+        global = true,
+        help = "Always split where clauses; disable with `off`",
+        default_value_t = match FormatConfig::default().split_attributes {
+            true => Offable::On(On),
+            false => Offable::Off,
+        },
+    )]
+    split_where: Offable<On>,
+    #[arg(
+        long,
+        global = true,
+        help = "Use a max comment length relative to start of comment (i.e. ignoring indentation); disable with `off`",
+        default_value_t = match FormatConfig::default().comment_width {
+            Some(x) => Offable::On(x),
+            None => Offable::Off,
+        },
+    )]
+    comment_length: Offable<usize>,
+    #[arg(
+        long,
+        global = true,
+        help = "Problems formatting comments are fatal; disable with `false`",
+        default_value_t = match FormatConfig::default().comment_errors_fatal {
+            true => Offable::On(On),
+            false => Offable::Off,
+        },
+    )]
+    comment_errors_fatal: Offable<On>,
+    #[arg(
+        long,
+        global = true,
+        help = "Verify internal line bookkeeping after every change (very slow, for debugging)",
+    )]
+    debug_checks: bool,
+    #[arg(
+        long,
+        global = true,
+        help =
+            "Format each top-level item separately to reduce memory use (files are still parsed whole); always on for files over --item-by-item-threshold",
+    )]
+    item_by_item: bool,
+    #[arg(
+        long,
+        global = true,
+        value_name = "BYTES",
+        help = "Format files larger than this item by item; disable with `off`",
+        default_value_t = Offable::On(ITEM_BY_ITEM_THRESHOLD),
+    )]
+    item_by_item_threshold: Offable<usize>,
+    #[arg(
+        long,
+        global = true,
+        help =
+            "Leave `//` comments at the end of a line there if the line fits, rather than moving them above the line",
+    )]
+    keep_trailing_comments: bool,
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        help =
+            "Write comments on their own lines as `//` (line) or `/* */` (block) comments, or keep the style they were written in (preserve)",
+        default_value_t = match FormatConfig::default().comment_style {
+            None => CommentStyleArg::Preserve,
+            Some(CommentStyle::Line) => CommentStyleArg::Line,
+            Some(CommentStyle::Block) => CommentStyleArg::Block,
+        },
+    )]
+    comment_style: CommentStyleArg,
+    #[arg(
+        long,
+        global = true,
+        help = "Treat non-doc comments that look like commented-out Rust code as verbatim `//.` comments",
+    )]
+    detect_commented_code: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy)]
+pub enum CommentStyleArg {
+    Preserve,
+    Line,
+    Block,
+}
+
+impl Display for CommentStyleArg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommentStyleArg::Preserve => "preserve",
+            CommentStyleArg::Line => "line",
+            CommentStyleArg::Block => "block",
+        }.fmt(f)
+    }
+}
+
+impl FormatArgs {
+    pub fn config(&self) -> FormatConfig {
+        FormatConfig {
+            quiet: self.quiet,
+            max_width: self.line_length,
+            root_splits: self.root_splits,
+            split_brace_threshold: match self.split_brace_threshold {
+                Offable::Off => None,
+                Offable::On(n) => Some(n),
+            },
+            split_attributes: match self.split_attributes {
+                Offable::Off => false,
+                Offable::On(_) => true,
+            },
+            split_where: match self.split_where {
+                Offable::Off => false,
+                Offable::On(_) => true,
+            },
+            comment_width: match self.comment_length {
+                Offable::Off => None,
+                Offable::On(n) => Some(n),
+            },
+            comment_errors_fatal: match self.comment_errors_fatal {
+                Offable::Off => false,
+                Offable::On(_) => true,
+            },
+            debug_checks: self.debug_checks,
+            item_by_item: self.item_by_item,
+            item_by_item_threshold: match self.item_by_item_threshold {
+                Offable::Off => None,
+                Offable::On(n) => Some(n),
+            },
+            keep_trailing_comments: self.keep_trailing_comments,
+            comment_style: match self.comment_style {
+                CommentStyleArg::Preserve => None,
+                CommentStyleArg::Line => Some(CommentStyle::Line),
+                CommentStyleArg::Block => Some(CommentStyle::Block),
+            },
+            detect_commented_code: self.detect_commented_code,
+        }
+    }
+}
+
+/// Options for finding and processing files shared by the command line tools.
+#[derive(clap::Args, Clone)]
+pub struct FilesArgs {
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        help = "Cargo.toml to use instead of the nearest one above the current dir",
+    )]
+    pub manifest_path: Option<PathBuf>,
+    #[arg(
+        long,
+        global = true,
+        help =
+            "When formatting packages, find files by following `mod` declarations from each target's root file instead of taking every `.rs` file in the source dirs",
+    )]
+    pub follow_mods: bool,
+    #[arg(
+        long,
+        global = true,
+        value_name = "GLOB",
+        help = "Skip matching files and directories when looking for files (gitignore syntax); can be repeated",
+    )]
+    exclude: Vec<String>,
+    #[arg(long, help = "Limits threads to specified count")]
+    pub thread_count: Option<usize>,
+    #[arg(long, help = "Skip files recorded as already formatted, and record newly formatted ones")]
+    cache: bool,
+    #[arg(
+        long,
+        help = "Directory to record already-formatted files in (implies `--cache`); defaults to `genemichaels` in the cargo target dir",
+    )]
+    cache_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with = "check",
+        help =
+            "Write formatted copies of the files to a mirrored tree under DIR instead of modifying them, then list the files that differ",
+    )]
+    pub output_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        conflicts_with_all = ["check", "output_dir"],
+        help =
+            "Format every file before writing any, and only write if all of them succeeded so a failure leaves the files untouched",
+    )]
+    pub atomic: bool,
+}
+
+impl FilesArgs {
+    pub fn excludes(&self) -> Result<Excludes> {
+        self.excludes_from(&current_dir()?)
+    }
+
+    /// Like `excludes` but looks for the config file starting in `dir` instead of the current
+    /// dir.
+    pub fn excludes_from(&self, dir: &Path) -> Result<Excludes> {
+        Excludes::new(&self.exclude, load_config_file(dir)?, self.output_dir.as_deref())
+    }
+
+    /// Where formatted files go given `--output-dir` and whether `--check` was passed.
+    pub fn output(&self, check: bool) -> Result<Output> {
+        Ok(if let Some(dir) = &self.output_dir {
+            Output::Mirror(Arc::new(Mirror::new(dir.clone())?))
+        } else if self.atomic {
+            Output::Atomic(Arc::new(Mutex::new(vec![])))
+        } else if check {
+            Output::Check
+        } else {
+            Output::InPlace
+        })
+    }
+
+    /// The cache if enabled, by default in the target dir of `project_dir` or of the Cargo
+    /// project containing the current dir.
+    pub fn cache(&self, config: &FormatConfig, project_dir: Option<&Path>) -> Option<FormatCache> {
+        if let Some(dir) = &self.cache_dir {
+            return FormatCache::new(dir.clone(), config);
+        }
+        if !self.cache {
+            return None;
+        }
+        let project_dir = match project_dir {
+            Some(d) => d.to_path_buf(),
+            None => find_cargo_toml(&current_dir().ok()?)?.parent()?.to_path_buf(),
+        };
+        FormatCache::new(default_cache_dir(&project_dir), config)
+    }
+}
+
+/// What to do with formatted output.
+#[derive(Clone)]
+pub enum Output {
+    /// Overwrite the original files
+    InPlace,
+    /// Don't write anything, report files that would change
+    Check,
+    /// Write a copy of every file to another directory
+    Mirror(Arc<Mirror>),
+    /// Overwrite the original files, but only once all of them have been formatted
+    Atomic(Arc<Mutex<Vec<(PathBuf, String)>>>),
+}
+
+impl Output {
+    /// Does anything left over once all files have been processed - prints the differing
+    /// files or writes the held back files if nothing `failed`.
+    pub fn finish(&self, quiet: bool, failed: bool) -> Result<()> {
+        match self {
+            Output::InPlace | Output::Check => { },
+            Output::Mirror(m) => m.summarize(quiet),
+            Output::Atomic(pending) => {
+                let pending = pending.lock().unwrap();
+                if failed {
+                    if !quiet {
+                        eprintln!(
+                            "\x1B[1;33m     Aborted\x1B[0;22m not writing {} formatted file(s) since some files failed",
+                            pending.len()
+                        );
+                    }
+                } else {
+                    write_all(&pending)?;
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Writes each file to a temporary file next to it and only renames them into place
+/// once all writes have succeeded.
+fn write_all(files: &[(PathBuf, String)]) -> Result<()> {
+    let mut temps = vec![];
+    let mut res = Ok(());
+    for (file_path, contents) in files {
+        let mut temp_name = file_path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".genemichaels-tmp");
+        let temp_path = file_path.with_file_name(temp_name);
+        res =
+            fs::write(
+                &temp_path,
+                contents.as_bytes(),
+            ).and_then(|_| fs::set_permissions(&temp_path, fs::metadata(file_path)?.permissions()));
+        if res.is_err() {
+            let _ = fs::remove_file(&temp_path);
+            break;
+        }
+        temps.push((temp_path, file_path));
+    }
+    if let Err(e) = res {
+        for (temp_path, _) in temps {
+            let _ = fs::remove_file(temp_path);
+        }
+        return Err(e.into());
+    }
+    for (temp_path, file_path) in temps {
+        fs::rename(temp_path, file_path)?;
+    }
+    Ok(())
+}
+
+/// Writes files to the same relative path under another directory, remembering which
+/// ones changed.
+pub struct Mirror {
+    dir: PathBuf,
+    current_dir: PathBuf,
+    differing: Mutex<Vec<PathBuf>>,
+}
+
+impl Mirror {
+    pub fn new(dir: PathBuf) -> Result<Mirror> {
+        Ok(Mirror {
+            dir,
+            current_dir: current_dir()?,
+            differing: Mutex::new(vec![]),
+        })
+    }
+
+    /// Where the copy of `file_path` goes. Files outside the current dir are placed by their
+    /// absolute path.
+    pub fn mirror_path(&self, file_path: &Path) -> PathBuf {
+        let abs = normalize_path(&self.current_dir.join(file_path));
+        let rel = abs.strip_prefix(&self.current_dir).unwrap_or(&abs);
+        let mut out = self.dir.clone();
+        out.extend(rel.components().filter(|c| matches!(c, Component::Normal(_))));
+        out
+    }
+
+    pub fn write(&self, file_path: &Path, contents: &str, differs: bool) -> Result<()> {
+        let out_path = self.mirror_path(file_path);
+        if let Some(parent) = out_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&out_path, contents.as_bytes())?;
+        if differs {
+            self.differing.lock().unwrap().push(file_path.to_path_buf());
+        }
+        Ok(())
+    }
+
+    /// Lists the files whose copies differ on stdout.
+    pub fn summarize(&self, quiet: bool) {
+        let mut differing = self.differing.lock().unwrap();
+        differing.sort();
+        if !quiet {
+            eprintln!(
+                "\x1B[1;32m     Written\x1B[0;22m copies to {}, {} differ from the originals:",
+                self.dir.to_string_lossy(),
+                differing.len()
+            );
+        }
+        for file_path in differing.iter() {
+            println!("{}", file_path.to_string_lossy());
+        }
+    }
+}
+
+pub fn skip(src: &str) -> bool {
+    src.lines().take(5).any(|l| l.contains("`nogenemichaels`"))
+}
+
+/// Formats Rust source, warning about comments that had to be moved. `name` is only
+/// used in the warnings.
+pub fn process(config: &FormatConfig, name: &Path, source: &str) -> Result<String> {
+    let res = format_str(source, config)?;
+    if !config.quiet {
+        for moved in &res.moved_comments {
+            print_warning_text();
+            eprintln!("{}: {}", name.to_string_lossy(), moved);
+        }
+    }
+    if !res.lost_comments.is_empty() {
+        return Err(
+            anyhow!(
+                "The following comments were missed during formatting: {:?}",
+                res.lost_comments.values().flatten().collect::<Vec<&Comment>>()
+            ),
+        );
+    }
+    match syn::parse_str::<File>(&res.rendered) {
+        Ok(_) => { },
+        Err(e) => {
+            return Err(
+                anyhow!(
+                    "Rendered document couldn't be re-parsed in verification step at {}:{}: {}\n\n{}",
+                    e.span().start().line,
+                    e.span().start().column,
+                    e,
+                    res
+                        .rendered
+                        .lines()
+                        .enumerate()
+                        .skip(e.span().start().line.saturating_sub(5))
+                        .take(10)
+                        .map(|(ln, l)| format!("{:0>4} {}", ln + 1, l))
+                        .collect::<Vec<String>>()
+                        .join("\n")
+                ),
+            );
+        },
+    };
+    Ok(res.rendered)
+}
+
+pub enum FileOutcome {
+    Formatted,
+    // Checking and the formatted output differs
+    Unformatted,
+    // Known to be formatted already from the cache
+    Unchanged,
+    Skipped,
+}
+
+/// Records hashes of file contents known to be formatted output, so they can be
+/// skipped next time. Keys include the formatter executable's size and modification
+/// time and the config, so rebuilding the formatter or changing the config
+/// invalidates everything.
+pub struct FormatCache {
+    dir: PathBuf,
+    build_key: String,
+    config_key: String,
+}
+
+impl FormatCache {
+    /// `None` if the formatter executable can't be found to identify the build.
+    pub fn new(dir: PathBuf, config: &FormatConfig) -> Option<FormatCache> {
+        let exe = fs::metadata(env::current_exe().ok()?).ok()?;
+        Some(FormatCache {
+            dir,
+            build_key: format!("{} {:?}", exe.len(), exe.modified().ok()?),
+            // Options that don't affect the output are normalized so they don't affect the key
+            config_key: format!("{:?}", FormatConfig {
+                quiet: false,
+                debug_checks: false,
+                item_by_item: false,
+                item_by_item_threshold: None,
+                ..*config
+            }),
+        })
+    }
+
+    fn entry_path(&self, source: &str) -> PathBuf {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.build_key);
+        hasher.update([0u8]);
+        hasher.update(&self.config_key);
+        hasher.update([0u8]);
+        hasher.update(source);
+        self.dir.join(format!("{:x}", hasher.finalize()))
+    }
+
+    pub fn is_formatted(&self, source: &str) -> bool {
+        self.entry_path(source).exists()
+    }
+
+    pub fn mark_formatted(&self, source: &str) {
+        // The cache is only an optimization, don't fail formatting if it can't be written
+        let _ = fs::create_dir_all(&self.dir).and_then(|_| fs::write(self.entry_path(source), []));
+    }
+}
+
+/// Finds the nearest Cargo.toml at or above `start`.
+pub fn find_cargo_toml(start: &Path) -> Option<PathBuf> {
+    for d in start.ancestors() {
+        let cargo_toml_path = d.join(CARGO_TOML);
+        if cargo_toml_path.exists() && cargo_manifest::Manifest::from_path(&cargo_toml_path).is_ok() {
+            return Some(cargo_toml_path);
+        }
+    }
+    None
+}
+
+/// Finds the nearest dir at or above `start` with a Cargo.toml declaring a workspace.
+fn find_workspace_root(start: &Path) -> Option<&Path> {
+    start
+        .ancestors()
+        .find(
+            |d| cargo_manifest::Manifest::from_path(d.join(CARGO_TOML))
+                .map(|m| m.workspace.is_some())
+                .unwrap_or(false),
+        )
+}
+
+pub fn default_cache_dir(project_dir: &Path) -> PathBuf {
+    let target_dir = match env::var_os("CARGO_TARGET_DIR") {
+        Some(d) => PathBuf::from(d),
+        None => {
+            // Workspace members share the workspace's target dir
+            find_workspace_root(project_dir).unwrap_or(project_dir).join("target")
+        },
+    };
+    target_dir.join("genemichaels")
+}
+
+/// Markdown files (named directly) only have their Rust code blocks formatted.
+pub fn is_markdown(file_path: &Path) -> bool {
+    matches!(file_path.extension().and_then(|e| e.to_str()), Some("md" | "markdown"))
+}
+
+/// Like `process` but for a file that may be markdown.
+pub fn process_path(config: &FormatConfig, file_path: &Path, source: &str) -> Result<String> {
+    if is_markdown(file_path) {
+        format_md_code_blocks(source, config)
+    } else {
+        process(config, file_path, source)
+    }
+}
+
+pub fn process_file(
+    config: &FormatConfig,
+    cache: Option<&FormatCache>,
+    output: &Output,
+    file_path: &Path,
+) -> Result<FileOutcome> {
+    let source = String::from_utf8(fs::read(file_path)?)?;
+    if skip(&source) {
+        if let Output::Mirror(m) = output {
+            m.write(file_path, &source, false)?;
+        }
+        return Ok(FileOutcome::Skipped);
+    }
+    if let Some(cache) = cache {
+        if cache.is_formatted(&source) {
+            if let Output::Mirror(m) = output {
+                m.write(file_path, &source, false)?;
+            }
+            return Ok(FileOutcome::Unchanged);
+        }
+    }
+    let out = process_path(config, file_path, &source)?;
+    if let Some(cache) = cache {
+        cache.mark_formatted(&out);
+    }
+    match output {
+        Output::Mirror(m) => {
+            m.write(file_path, &out, out != source)?;
+            Ok(FileOutcome::Formatted)
+        },
+        _ if out == source => Ok(FileOutcome::Formatted),
+        Output::Atomic(pending) => {
+            pending.lock().unwrap().push((file_path.to_path_buf(), out));
+            Ok(FileOutcome::Formatted)
+        },
+        Output::InPlace => {
+            fs::write(file_path, out.as_bytes())?;
+            Ok(FileOutcome::Formatted)
+        },
+        Output::Check => Ok(FileOutcome::Unformatted),
+    }
+}
+
+pub fn report_file(config: &FormatConfig, file_path: &Path, res: Result<FileOutcome>) {
+    if config.quiet {
+        return;
+    }
+    match res {
+        Ok(FileOutcome::Formatted) => {
+            eprintln!("\x1B[1;32m   Formatted\x1B[0;22m {}", file_path.to_string_lossy());
+        },
+        Ok(FileOutcome::Unformatted) => {
+            eprintln!("\x1B[1;33m Unformatted\x1B[0;22m {}", file_path.to_string_lossy());
+        },
+        Ok(FileOutcome::Unchanged) => {
+            eprintln!("\x1B[1;32m   Unchanged\x1B[0;22m {}", file_path.to_string_lossy());
+        },
+        Ok(FileOutcome::Skipped) => {
+            print_skipping_text();
+            eprintln!("{}", file_path.to_string_lossy());
+        },
+        Err(e) => {
+            print_error_text();
+            eprintln!("formatting {}: {:?}", file_path.to_string_lossy(), e);
+        },
+    }
+}
+
+/// Settings from `.genemichaels.toml` in the current dir or the nearest parent with
+/// one.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ConfigFile {
+    /// Paths to skip when looking for files, in gitignore syntax relative to the config file
+    exclude: Vec<String>,
+}
+
+pub fn load_config_file(start: &Path) -> Result<Option<(PathBuf, ConfigFile)>> {
+    for d in start.ancestors() {
+        let config_path = d.join(CONFIG_FILE);
+        if config_path.exists() {
+            let config =
+                toml::from_str(
+                    &fs::read_to_string(&config_path)?,
+                ).map_err(|e| anyhow!("Error parsing {}: {}", config_path.to_string_lossy(), e))?;
+            return Ok(Some((d.to_path_buf(), config)));
+        }
+    }
+    Ok(None)
+}
+
+/// Paths to leave out when looking for files, from `--exclude` (relative to the
+/// current dir) and the config file (relative to the config file).
+#[derive(Clone)]
+pub struct Excludes {
+    current_dir: PathBuf,
+    globs: Arc<Vec<Gitignore>>,
+    // Don't pick up formatted copies when they're written inside the tree
+    output_dir: Option<PathBuf>,
+}
+
+impl Excludes {
+    pub fn new(
+        args_exclude: &[String],
+        config_file: Option<(PathBuf, ConfigFile)>,
+        output_dir: Option<&Path>,
+    ) -> Result<Excludes> {
+        fn build(root: &Path, globs: &[String]) -> Result<Gitignore> {
+            let mut builder = GitignoreBuilder::new(root);
+            for glob in globs {
+                builder.add_line(None, glob)?;
+            }
+            Ok(builder.build()?)
+        }
+
+        let current_dir = current_dir()?;
+        let mut globs = vec![build(&current_dir, args_exclude)?];
+        if let Some((root, config)) = config_file {
+            globs.push(build(&root, &config.exclude)?);
+        }
+        Ok(Excludes {
+            output_dir: output_dir.map(|d| normalize_path(&current_dir.join(d))),
+            current_dir,
+            globs: Arc::new(globs),
+        })
+    }
+
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        let normalized = normalize_path(&self.current_dir.join(path));
+        if matches!(&self.output_dir, Some(d) if normalized.starts_with(d)) {
+            return true;
+        }
+        self
+            .globs
+            .iter()
+            .any(
+                |g| normalized.starts_with(g.path()) &&
+                    g.matched_path_or_any_parents(&normalized, is_dir).is_ignore(),
+            )
+    }
+}
+
+/// Removes `.` and `..` components without touching the filesystem. The exclude
+/// matchers compare paths textually so this is needed before matching.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::CurDir => { },
+            Component::ParentDir => {
+                normalized.pop();
+            },
+            c => normalized.push(c),
+        }
+    }
+    normalized
+}
+
+/// Adds `.rs` files under `dir` to `files`, skipping any already in `seen`. Files
+/// ignored by git, `.genemichaelsignore` files, or `excludes` are left out.
+pub fn collect_rs_files(dir: &Path, excludes: &Excludes, seen: &mut HashSet<PathBuf>, files: &mut Vec<PathBuf>) {
+    let filter_excludes = excludes.clone();
+    let walk =
+        WalkBuilder::new(dir)
+            .hidden(false)
+            .ignore(false)
+            .add_custom_ignore_filename(IGNORE_FILE)
+            .sort_by_file_name(|a, b| a.cmp(b))
+            .filter_entry(
+                move |e| !filter_excludes.is_excluded(
+                    e.path(),
+                    e.file_type().map(|t| t.is_dir()).unwrap_or(false),
+                ),
+            )
+            .build();
+    for f in walk {
+        match f {
+            Ok(file) => {
+                let file_path = file.path().to_path_buf();
+                if file_path.extension() != Some(OsStr::new("rs")) {
+                    continue;
+                }
+                add_file(file_path, seen, files);
+            },
+            Err(e) => {
+                eprintln!("Error opening file {}: {}", dir.to_string_lossy(), e);
+                continue;
+            },
+        }
+    }
+}
+
+/// Adds a file unless it's already present, comparing canonical paths since the same
+/// file can be reached through overlapping dirs (ex: bin/ inside src/) or symlinks.
+pub fn add_file(file_path: PathBuf, seen: &mut HashSet<PathBuf>, files: &mut Vec<PathBuf>) {
+    if seen.insert(fs::canonicalize(&file_path).unwrap_or_else(|_| file_path.clone())) {
+        files.push(file_path);
+    }
+}
+
+/// Runs `job` on each file on a single thread pool, largest files first, and reports
+/// the results in the order the files were given. Returns true if any file failed.
+pub fn process_files(
+    files: Vec<PathBuf>,
+    thread_count: Option<usize>,
+    config: FormatConfig,
+    job: impl Fn(&Path) -> Result<FileOutcome> + Send + Sync + 'static,
+) -> bool {
+    let pool = if let Some(t) = thread_count {
+        threadpool::Builder::new().num_threads(t)
+    } else {
+        threadpool::Builder::new()
+    }.build();
+    let (results_tx, results_rx) = mpsc::channel();
+    let job = Arc::new(job);
+
+    // Start with the largest files so a big one doesn't end up running alone at the end
+    let mut schedule =
+        files
+            .iter()
+            .enumerate()
+            .map(|(i, f)| (fs::metadata(f).map(|m| m.len()).unwrap_or(0), i))
+            .collect::<Vec<_>>();
+    schedule.sort_by_key(|(size, i)| (Reverse(*size), *i));
+    for (_, i) in schedule {
+        let file_path = files.get(i).unwrap().clone();
+        let results_tx = results_tx.clone();
+        let job = job.clone();
+        pool.execute(move || {
+            // Report a panic as this file's error, otherwise the files after it would never be reported
+            let res = panic::catch_unwind(AssertUnwindSafe(|| job(&file_path))).unwrap_or_else(|e| {
+                let message =
+                    e
+                        .downcast_ref::<&str>()
+                        .map(|m| m.to_string())
+                        .or_else(|| e.downcast_ref::<String>().cloned())
+                        .unwrap_or_default();
+                Err(anyhow!("Formatter panicked: {}", message))
+            });
+            results_tx.send((i, res)).unwrap();
+        });
+    }
+    drop(results_tx);
+
+    // Report in input order, holding on to results that finish early
+    let mut failed = false;
+    let mut pending = HashMap::new();
+    let mut next = 0usize;
+    for (i, res) in results_rx {
+        pending.insert(i, res);
+        while let Some(res) = pending.remove(&next) {
+            failed = failed || matches!(res, Err(_) | Ok(FileOutcome::Unformatted));
+            report_file(&config, files.get(next).unwrap(), res);
+            next += 1;
+        }
+    }
+
+    // A worker died without reporting, report the files after it anyway
+    for (i, file_path) in files.iter().enumerate().skip(next) {
+        let res = pending.remove(&i).unwrap_or_else(|| Err(anyhow!("Formatting stopped without a result")));
+        failed = failed || matches!(res, Err(_) | Ok(FileOutcome::Unformatted));
+        report_file(&config, file_path, res);
+    }
+    failed
+}
+
+/// A package to format, from `cargo metadata` or read from the manifests directly.
+pub struct PackageInfo {
+    name: String,
+    pub dir: PathBuf,
+    /// Root source files of the package's targets, except the build script
+    targets: Vec<PathBuf>,
+    build: Option<PathBuf>,
+}
+
+/// The packages in the workspace around a manifest, for each binary to pick from with
+/// its own flags.
+pub struct Workspace {
+    pub manifest_path: PathBuf,
+    pub manifest: cargo_manifest::Manifest,
+    packages: Vec<PackageInfo>,
+}
+
+impl Workspace {
+    /// Lists the packages around `manifest_path`, or the nearest Cargo.toml. Without cargo
+    /// the manifests are read directly, starting at the workspace root if `whole` or else at
+    /// the manifest.
+    pub fn load(manifest_path: Option<&Path>, whole: bool) -> Result<Workspace> {
+        let manifest_path = match manifest_path {
+            Some(p) => p.to_path_buf(),
+            None => find_cargo_toml(&current_dir()?).ok_or_else(|| anyhow::anyhow!("No Cargo.toml found!"))?,
+        };
+        let manifest_path = fs::canonicalize(&manifest_path)?;
+        let manifest_dir = manifest_path.parent().expect("Unable to get parent of Cargo.toml");
+        let manifest = cargo_manifest::Manifest::from_path(&manifest_path)?;
+        let packages = match cargo_metadata_packages(&manifest_path) {
+            Ok(p) => p,
+            Err(_) => {
+                // No cargo, read the manifests instead
+                let root = match whole {
+                    true => find_workspace_root(manifest_dir).unwrap_or(manifest_dir),
+                    false => manifest_dir,
+                };
+                let mut packages = vec![];
+                add_manifest_packages(root, &mut packages)?;
+                packages
+            },
+        };
+        Ok(Workspace {
+            manifest_path,
+            manifest,
+            packages,
+        })
+    }
+
+    /// Picks the package at the manifest, or every package if `all`.
+    pub fn at_manifest(self, all: bool) -> (PathBuf, Vec<PackageInfo>) {
+        let manifest_dir = self.manifest_path.parent().map(Path::to_path_buf);
+        let packages =
+            self.packages.into_iter().filter(|p| all || fs::canonicalize(&p.dir).ok() == manifest_dir).collect();
+        (self.manifest_path, packages)
+    }
+
+    /// Picks the packages with these names, failing if one isn't in the workspace.
+    pub fn named(self, names: &[String]) -> Result<(PathBuf, Vec<PackageInfo>)> {
+        for name in names {
+            if !self.packages.iter().any(|p| &p.name == name) {
+                return Err(anyhow!("No package named {} in the workspace", name));
+            }
+        }
+        let packages = self.packages.into_iter().filter(|p| names.contains(&p.name)).collect();
+        Ok((self.manifest_path, packages))
+    }
+}
+
+/// Formats the selected packages' files, `manifest_path` being the manifest they were
+/// picked from. Returns true if any file failed (or, when checking, needs formatting).
+pub fn process_packages(
+    files_args: &FilesArgs,
+    manifest_path: &Path,
+    packages: &[PackageInfo],
+    excludes: &Excludes,
+    config: FormatConfig,
+    output: Output,
+) -> bool {
+    let inst = time::Instant::now();
+    if !config.quiet {
+        eprintln!("\x1B[1;32m  Formatting\x1B[0;22m workspace...");
+    }
+    let cache = files_args.cache(&config, manifest_path.parent());
+
+    // Collect everything first so the work can be spread over a single pool
+    let files = package_files(packages, files_args.follow_mods, excludes);
+    let failed =
+        process_files(
+            files,
+            files_args.thread_count,
+            config,
+            move |f| process_file(&config, cache.as_ref(), &output, f),
+        );
+    print_finished(inst, config.quiet, failed);
+    failed
+}
+
+/// Prints how formatting went and how long it took, unless `quiet`.
+pub fn print_finished(inst: time::Instant, quiet: bool, failed: bool) {
+    if quiet {
+        return;
+    }
+    let elapsed = time::Instant::now().duration_since(inst).as_secs_f64();
+    if failed {
+        eprintln!("\x1B[1;38;5;9m    Finished\x1B[0;22m workspace formatting with failures in {:.2}s", elapsed);
+    } else {
+        eprintln!("\x1B[1;32m    Finished\x1B[0;22m workspace formatted successfully in {:.2}s", elapsed);
+    }
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    packages: Vec<MetadataPackage>,
+}
+
+#[derive(Deserialize)]
+struct MetadataPackage {
+    name: String,
+    manifest_path: PathBuf,
+    targets: Vec<MetadataTarget>,
+}
+
+#[derive(Deserialize)]
+struct MetadataTarget {
+    kind: Vec<String>,
+    src_path: PathBuf,
+}
+
+/// Lists the packages in the workspace containing the manifest using `cargo metadata`
+/// , which handles everything cargo does (globs, excludes, inherited settings, target
+/// auto-discovery).
+fn cargo_metadata_packages(manifest_path: &Path) -> Result<Vec<PackageInfo>> {
+    let output =
+        process::Command::new(env::var_os("CARGO").unwrap_or_else(|| "cargo".into()))
+            .args(["metadata", "--no-deps", "--offline", "--format-version", "1", "--manifest-path"])
+            .arg(manifest_path)
+            .stderr(Stdio::null())
+            .output()?;
+    if !output.status.success() {
+        return Err(anyhow!("cargo metadata failed"));
+    }
+    let metadata: Metadata = serde_json::from_slice(&output.stdout)?;
+    Ok(metadata.packages.into_iter().map(|p| {
+        let mut targets = vec![];
+        let mut build = None;
+        for t in p.targets {
+            if t.kind.iter().any(|k| k == "custom-build") {
+                build = Some(t.src_path);
+            } else {
+                targets.push(t.src_path);
+            }
+        }
+        PackageInfo {
+            name: p.name,
+            dir: p.manifest_path.parent().unwrap_or(Path::new("")).to_path_buf(),
+            targets,
+            build,
+        }
+    }).collect())
+}
+
+/// Adds the package in `path` and any workspace members its manifest lists.
+fn add_manifest_packages(path: &Path, packages: &mut Vec<PackageInfo>) -> Result<()> {
+    let manifest = cargo_manifest::Manifest::from_path(path.join(CARGO_TOML))?;
+    let members = workspace_members(path, &manifest)?;
+    packages.extend(manifest_package(path, manifest)?);
+    for member in members {
+        if !packages.iter().any(|p| p.dir == member) {
+            add_manifest_packages(&member, packages)?;
+        }
+    }
+    Ok(())
+}
+
+/// Directories of the workspace members listed in the manifest, expanding globs and
+/// leaving out `workspace.exclude`d dirs.
+fn workspace_members(path: &Path, manifest: &Manifest) -> Result<Vec<PathBuf>> {
+    let ws = match &manifest.workspace {
+        Some(ws) => ws,
+        None => return Ok(vec![]),
+    };
+    let exclude = ws.exclude.iter().flatten().map(|e| path.join(e)).collect::<Vec<_>>();
+    let base = glob::Pattern::escape(&path.to_string_lossy());
+    let mut members = vec![];
+    for member in &ws.members {
+        for dir in glob::glob(&format!("{}/{}", base, member))? {
+            let dir = dir?;
+            if !dir.join(CARGO_TOML).exists() || exclude.iter().any(|e| dir.starts_with(e)) ||
+                members.contains(&dir) {
+                continue;
+            }
+            members.push(dir);
+        }
+    }
+    Ok(members)
+}
+
+/// Reads a package's targets from its manifest, for when `cargo metadata` isn't
+/// available.
+fn manifest_package(path: &Path, manifest: Manifest) -> Result<Option<PackageInfo>> {
+    let package = match manifest.package {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+    let mut targets = vec![];
+
+    // Targets without an explicit path are in the default location for their name
+    if let Some(lib) = manifest.lib {
+        targets.push(path.join(lib.path.as_deref().unwrap_or("src/lib.rs")));
+    }
+    for (
+        products,
+        dir,
+    ) in [
+        (manifest.bin, "src/bin"),
+        (manifest.test, "tests"),
+        (manifest.example, "examples"),
+        (manifest.bench, "benches"),
+    ] {
+        for product in products.into_iter().flatten() {
+            match (product.path, product.name) {
+                (Some(p), _) => targets.push(path.join(p)),
+                (None, Some(name)) => {
+                    let mut candidates =
+                        vec![
+                            path.join(dir).join(format!("{}.rs", name)),
+                            path.join(dir).join(&name).join("main.rs")
+                        ];
+                    if dir == "src/bin" {
+                        candidates.push(path.join("src/main.rs"));
+                    }
+                    targets.extend(candidates.into_iter().find(|p| p.exists()));
+                },
+                (None, None) => { },
+            }
+        }
+    }
+
+    // Read separately from the manifest since `build` may be a path or `false`
+    let raw = fs::read_to_string(path.join(CARGO_TOML))?.parse::<toml::Value>()?;
+    let build = match raw.get("package").and_then(|p| p.get("build")) {
+        Some(toml::Value::String(p)) => Some(path.join(p)),
+        Some(toml::Value::Boolean(false)) => None,
+        _ => Some(path.join("build.rs")).filter(|p| p.exists()),
+    };
+    Ok(Some(PackageInfo {
+        name: package.name,
+        dir: path.to_path_buf(),
+        targets,
+        build,
+    }))
+}
+
+fn process_dirs(package: &PackageInfo) -> HashSet<PathBuf> {
+    let path = &package.dir;
+    let mut dirs = HashSet::from([]);
+    for target in &package.targets {
+        dirs.insert(target.parent().unwrap().to_owned());
+    }
+
+    // default bins location
+    if path.join("bin").exists() {
+        dirs.insert(path.join("bin"));
+    }
+
+    // default benches location
+    if path.join("benches").exists() {
+        dirs.insert(path.join("benches"));
+    }
+
+    // default tests location
+    if path.join("tests").exists() {
+        dirs.insert(path.join("tests"));
+    }
+
+    // default examples location
+    if path.join("examples").exists() {
+        dirs.insert(path.join("examples"));
+    }
+
+    // add src if exists
+    if path.join("src").exists() {
+        dirs.insert(path.join("src"));
+    };
+    dirs
+}
+
+/// Paths from `#[path = "..."]` and `#[cfg_attr(..., path = "...")]` attributes.
+/// Since the `cfg` can't be evaluated here, paths from `cfg_attr` are all used along
+/// with the default location.
+fn mod_path_attrs(attrs: &[Attribute]) -> (Vec<String>, Vec<String>) {
+    let mut paths = vec![];
+    let mut cfg_paths = vec![];
+    for attr in attrs {
+        match attr.parse_meta() {
+            Ok(Meta::NameValue(nv)) if nv.path.is_ident("path") => {
+                if let Lit::Str(p) = nv.lit {
+                    paths.push(p.value());
+                }
+            },
+            Ok(Meta::List(l)) if l.path.is_ident("cfg_attr") => {
+                for nested in l.nested.into_iter().skip(1) {
+                    if let NestedMeta::Meta(Meta::NameValue(nv)) = nested {
+                        if let (true, Lit::Str(p)) = (nv.path.is_ident("path"), nv.lit) {
+                            cfg_paths.push(p.value());
+                        }
+                    }
+                }
+            },
+            _ => { },
+        }
+    }
+    (paths, cfg_paths)
+}
+
+/// Adds a module file and the files of any `mod` declarations in it. `mod_dir` is
+/// where its child modules' files are: the file's dir for crate roots, `mod.rs`, and
+/// `#[path]` files, or a dir named after the module otherwise.
+fn collect_mod_files(
+    file_path: &Path,
+    mod_dir: &Path,
+    excludes: &Excludes,
+    seen: &mut HashSet<PathBuf>,
+    files: &mut Vec<PathBuf>,
+) {
+    if !file_path.exists() || excludes.is_excluded(file_path, false) {
+        return;
+    }
+    let count = files.len();
+    add_file(file_path.to_path_buf(), seen, files);
+    if files.len() == count {
+        // Already visited
+        return;
+    }
+
+    // Files that can't be parsed are still formatted so the error gets reported
+    let ast = match fs::read_to_string(file_path).ok().and_then(|s| syn::parse_file(&s).ok()) {
+        Some(a) => a,
+        None => return,
+    };
+    let file_dir = file_path.parent().unwrap_or(Path::new(""));
+    collect_item_mod_files(&ast.items, file_dir, mod_dir, excludes, seen, files);
+}
+
+/// `path_dir` is what `#[path]` attributes are relative to - the file's dir outside
+/// of inline modules, `mod_dir` inside them.
+fn collect_item_mod_files(
+    items: &[Item],
+    path_dir: &Path,
+    mod_dir: &Path,
+    excludes: &Excludes,
+    seen: &mut HashSet<PathBuf>,
+    files: &mut Vec<PathBuf>,
+) {
+    for item in items {
+        let m = match item {
+            Item::Mod(m) => m,
+            _ => continue,
+        };
+        let name = m.ident.unraw().to_string();
+        let (paths, cfg_paths) = mod_path_attrs(&m.attrs);
+        match &m.content {
+            Some((_, items)) => {
+                let dir = mod_dir.join(paths.first().unwrap_or(&name));
+                collect_item_mod_files(items, &dir, &dir, excludes, seen, files);
+            },
+            None => {
+                for p in paths.iter().chain(cfg_paths.iter()) {
+                    let file_path = path_dir.join(p);
+                    let child_dir = file_path.parent().unwrap_or(Path::new("")).to_path_buf();
+                    collect_mod_files(&file_path, &child_dir, excludes, seen, files);
+                }
+                if paths.is_empty() {
+                    let child_dir = mod_dir.join(&name);
+                    let file_path = mod_dir.join(format!("{}.rs", name));
+                    if file_path.exists() {
+                        collect_mod_files(&file_path, &child_dir, excludes, seen, files);
+                    } else {
+                        collect_mod_files(&child_dir.join("mod.rs"), &child_dir, excludes, seen, files);
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Lists the `.rs` files in the packages' source dirs, or with `follow_mods` the
+/// files reachable from the targets' root files through `mod` declarations.
+pub fn package_files(packages: &[PackageInfo], follow_mods: bool, excludes: &Excludes) -> Vec<PathBuf> {
+    let mut seen_files = HashSet::new();
+    let mut files = vec![];
+    if follow_mods {
+        for root in packages.iter().flat_map(|p| p.targets.iter().chain(p.build.iter())) {
+            let root_dir = root.parent().unwrap_or(Path::new("")).to_path_buf();
+            collect_mod_files(root, &root_dir, excludes, &mut seen_files, &mut files);
+        }
+        return files;
+    }
+    let mut dirs = packages.iter().flat_map(process_dirs).collect::<Vec<_>>();
+    dirs.sort();
+    dirs.dedup();
+    for dir in dirs {
+        collect_rs_files(&dir, excludes, &mut seen_files, &mut files);
+    }
+    for build in packages.iter().filter_map(|p| p.build.as_ref()) {
+        if build.exists() && !excludes.is_excluded(build, false) {
+            add_file(build.clone(), &mut seen_files, &mut files);
+        }
+    }
+    files
+}
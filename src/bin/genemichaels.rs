@@ -1,458 +1,610 @@
+mod common;
+
 use anyhow::anyhow;
-use cargo_manifest::Manifest;
-use clap::Parser;
+use clap::{
+    ArgAction,
+    Parser,
+    Subcommand,
+};
+use common::{
+    add_file,
+    collect_rs_files,
+    normalize_path,
+    package_files,
+    print_finished,
+    process,
+    process_file,
+    process_files,
+    process_packages,
+    process_path,
+    report_file,
+    skip,
+    Excludes,
+    FileOutcome,
+    FilesArgs,
+    FormatArgs,
+    FormatCache,
+    Output,
+    PackageInfo,
+    Result,
+    Workspace,
+    IGNORE_FILE,
+};
 use genemichaels::{
-    format_str,
     print_error_text,
-    print_skipping_text,
-    Comment,
     FormatConfig,
 };
+use ignore::gitignore::GitignoreBuilder;
 use std::{
-    collections::HashSet,
+    collections::{
+        HashMap,
+        HashSet,
+    },
     env::current_dir,
     ffi::OsStr,
-    fmt::Display,
     fs,
-    io::Read,
+    io::{
+        Read,
+        Write,
+    },
     path::{
         Path,
         PathBuf,
     },
-    process,
-    result,
-    str::FromStr,
+    process::{
+        self,
+        Stdio,
+    },
+    sync::Mutex,
+    thread,
     time,
 };
-use syn::File;
 
-type Result<T> = result::Result<T, anyhow::Error>;
-const CARGO_TOML: &str = "Cargo.toml";
+// How often `watch` checks for changes, and how long a file must go unchanged
+// before it's formatted (editors may write a file in several steps)
+const WATCH_POLL: time::Duration = time::Duration::from_millis(250);
+const WATCH_DEBOUNCE: time::Duration = time::Duration::from_millis(500);
+
+#[derive(Parser, Clone)]
+struct Args {
+    #[arg(help =
+        "Files or directories to format in place (`.md` files get their Rust code blocks formatted); if none specified formats stdin and writes result to stdout")]
+    files: Vec<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[arg(
+        short,
+        long,
+        global = true,
+        value_name = "NAME",
+        num_args = 0 ..= 1,
+        action = ArgAction::Append,
+        help =
+            "Formats the entire package using the Cargo.toml, including listed workspace members; with NAME formats just that workspace member (can be repeated)",
+    )]
+    package: Option<Vec<String>>,
+    #[arg(
+        long,
+        value_name = "REF",
+        num_args = 0 ..= 1,
+        default_missing_value = "HEAD",
+        conflicts_with_all = ["package", "files", "staged"],
+        help =
+            "Formats `.rs` files changed in the git working tree relative to REF (default HEAD), plus untracked files",
+    )]
+    changed: Option<String>,
+    #[arg(
+        long,
+        conflicts_with_all = ["package", "files", "output_dir", "atomic"],
+        help =
+            "Formats the staged version of staged `.rs` files and re-stages the result; unstaged changes are left alone",
+    )]
+    staged: bool,
+    #[arg(
+        long,
+        conflicts_with = "staged",
+        help = "Don't write anything, exit with an error if any files aren't formatted",
+    )]
+    check: bool,
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["files", "package", "changed", "staged"],
+        help =
+            "When formatting stdin, the path of the file being formatted; used to find the config file, apply ignore rules, and in messages, but never read or written",
+    )]
+    stdin_filepath: Option<PathBuf>,
+    #[command(flatten)]
+    files_args: FilesArgs,
+    #[command(flatten)]
+    format: FormatArgs,
+}
 
-#[derive(Clone)]
-enum Offable<T> {
-    Off,
-    On(T),
+#[derive(Subcommand, Clone)]
+enum Command {
+    /// Keep running, reformatting files whenever they change
+    Watch {
+        #[arg(help = "Files or directories to watch; if none specified watches the package (same as --package)")]
+        files: Vec<PathBuf>,
+    },
 }
 
-impl<T: Display> std::fmt::Display for Offable<T> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Offable::Off => "off".fmt(f),
-            Offable::On(x) => x.fmt(f),
+/// Lists the given files plus `.rs` files in the given directories.
+fn expand_files(paths: &[PathBuf], excludes: &Excludes) -> Vec<PathBuf> {
+    let mut seen_files = HashSet::new();
+    let mut files = vec![];
+    for file in paths {
+        if file.is_dir() {
+            collect_rs_files(file, excludes, &mut seen_files, &mut files);
+        } else {
+            add_file(file.clone(), &mut seen_files, &mut files);
         }
     }
+    files
 }
 
-impl<E: std::error::Error + Send + Sync + 'static, T: FromStr<Err = E> + Clone + Display> FromStr for Offable<T> {
-    type Err = anyhow::Error;
+/// Polls files for changes and reformats each one after it stops changing. The files
+/// from `discover` are listed again when one of them changes (it may have added a
+/// `mod`) or when a directory under `roots` containing them changes, so new files are
+/// picked up too. Directories with no listed files aren't watched.
+fn watch(config: &FormatConfig, roots: &[PathBuf], discover: impl Fn() -> Result<Vec<PathBuf>>) -> Result<()> {
+    fn stamp(path: &Path) -> Option<(time::SystemTime, u64)> {
+        let meta = fs::metadata(path).ok()?;
+        Some((meta.modified().ok()?, meta.len()))
+    }
 
-    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
-        if s == "off" {
-            Ok(Self::Off)
-        } else {
-            Ok(Self::On(T::from_str(s)?))
+    // Directories where new files may appear, the files' parents up to the roots
+    let dir_stamps = |files: &[PathBuf]| {
+        let mut dirs = HashMap::new();
+        for file_path in files {
+            for dir in file_path.ancestors().skip(1) {
+                if dirs.contains_key(dir) || !roots.iter().any(|r| dir.starts_with(r)) {
+                    break;
+                }
+                dirs.insert(dir.to_path_buf(), stamp(dir));
+            }
+        }
+        dirs
+    };
+    let mut files = discover()?;
+    let mut dirs = dir_stamps(&files);
+    let mut stamps = HashMap::new();
+    for file_path in &files {
+        stamps.insert(file_path.clone(), stamp(file_path));
+    }
+    if !config.quiet {
+        eprintln!("\x1B[1;32m    Watching\x1B[0;22m {} files...", stamps.len());
+    }
+
+    // Files that have changed and when they last changed
+    let mut pending = HashMap::new();
+    let mut rediscover = false;
+    loop {
+        thread::sleep(WATCH_POLL);
+        if rediscover || dirs.iter().any(|(dir, s)| stamp(dir) != *s) {
+            files = match discover() {
+                Ok(f) => f,
+                Err(e) => {
+                    print_error_text();
+                    eprintln!("listing files: {:?}", e);
+                    continue;
+                },
+            };
+            dirs = dir_stamps(&files);
+            rediscover = false;
+        }
+        let now = time::Instant::now();
+        for file_path in &files {
+            let s = stamp(file_path);
+            if stamps.get(file_path) != Some(&s) {
+                stamps.insert(file_path.clone(), s);
+                pending.insert(file_path.clone(), now);
+                rediscover = true;
+            }
+        }
+        let ready =
+            pending
+                .iter()
+                .filter(|(_, changed)| now.duration_since(**changed) >= WATCH_DEBOUNCE)
+                .map(|(f, _)| f.clone())
+                .collect::<Vec<_>>();
+        for file_path in ready {
+            pending.remove(&file_path);
+            if !file_path.exists() {
+                continue;
+            }
+            let res = process_watched_file(config, &file_path);
+            if res.is_ok() {
+                // Take the stamp after our own write so it isn't seen as a change
+                stamps.insert(file_path.clone(), stamp(&file_path));
+            }
+            report_file(config, &file_path, res);
         }
     }
 }
-#[derive(Clone)]
-struct On;
 
-impl std::fmt::Display for On {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        "on".fmt(f)
+/// Formats a file in place, unless it's edited while being formatted so the edit isn't
+/// overwritten. The edit is then seen as a change and the file is formatted again.
+fn process_watched_file(config: &FormatConfig, file_path: &Path) -> Result<FileOutcome> {
+    let source = String::from_utf8(fs::read(file_path)?)?;
+    if skip(&source) {
+        return Ok(FileOutcome::Skipped);
+    }
+    let out = process_path(config, file_path, &source)?;
+    if out != source {
+        if fs::read(file_path)? != source.as_bytes() {
+            return Err(anyhow!("File changed while being formatted, not writing"));
+        }
+        fs::write(file_path, out.as_bytes())?;
     }
+    Ok(FileOutcome::Formatted)
 }
 
-#[derive(Debug)]
-struct OnErr(String);
+/// Like `Excludes::is_excluded` but also checks the `.genemichaelsignore` and (within a git
+/// repository) `.gitignore` files above the file, for files named directly rather than
+/// found by walking a directory. `file_path` must be absolute but doesn't need to exist.
+fn is_ignored_file(excludes: &Excludes, file_path: &Path) -> bool {
+    if excludes.is_excluded(file_path, false) {
+        return true;
+    }
+    let normalized = normalize_path(file_path);
+    let git_root = normalized.ancestors().skip(1).find(|d| d.join(".git").exists());
 
-impl Display for OnErr {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.0.fmt(f)
+    // Check the closest ignore files first, since they can override the ones above
+    for dir in normalized.ancestors().skip(1) {
+        let mut names = vec![IGNORE_FILE];
+        if matches!(git_root, Some(root) if dir.starts_with(root)) {
+            names.push(".gitignore");
+        }
+        for name in names {
+            let ignore_path = dir.join(name);
+            if !ignore_path.is_file() {
+                continue;
+            }
+            let mut builder = GitignoreBuilder::new(dir);
+            if builder.add(&ignore_path).is_some() {
+                continue;
+            }
+            let ignore = match builder.build() {
+                Ok(i) => i,
+                Err(_) => continue,
+            };
+            match ignore.matched_path_or_any_parents(&normalized, false) {
+                ignore::Match::None => { },
+                ignore::Match::Ignore(_) => return true,
+                ignore::Match::Whitelist(_) => return false,
+            }
+        }
     }
+    false
 }
 
-impl std::error::Error for OnErr {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        None
+/// Runs git in `dir` and returns its stdout.
+fn git(dir: &Path, args: &[&str], stdin: Option<&[u8]>) -> Result<Vec<u8>> {
+    let mut child = process::Command::new("git").current_dir(dir).args(args).stdin(if stdin.is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    }).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().map_err(|e| anyhow!("Error running git: {}", e))?;
+    if let Some(stdin) = stdin {
+        child.stdin.take().unwrap().write_all(stdin)?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!("git {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim()));
     }
+    Ok(output.stdout)
 }
 
-impl FromStr for On {
-    type Err = OnErr;
+fn git_root() -> Result<PathBuf> {
+    let out = git(&current_dir()?, &["rev-parse", "--show-toplevel"], None)?;
+    Ok(PathBuf::from(String::from_utf8(out)?.trim_end_matches(['\r', '\n'])))
+}
 
-    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
-        if s == "on" {
-            Ok(On)
-        } else {
-            Err(OnErr(format!("[{}] not allowed, must be on or off", s)))
+/// Adds the `.rs` files from NUL-separated, root-relative paths output by git.
+fn add_git_rs_files(
+    root: &Path,
+    out: &[u8],
+    excludes: &Excludes,
+    seen: &mut HashSet<PathBuf>,
+    files: &mut Vec<PathBuf>,
+) {
+    for p in out.split(|b| *b == 0).filter(|p| !p.is_empty()) {
+        let file_path = root.join(String::from_utf8_lossy(p).as_ref());
+        if file_path.extension() != Some(OsStr::new("rs")) || excludes.is_excluded(&file_path, false) {
+            continue;
         }
+        add_file(file_path, seen, files);
     }
 }
 
-#[derive(Parser, Clone)]
-struct Args {
-    #[arg(help = "Files to format in place; if none specified formats stdin and writes result to stdout")]
-    files: Vec<PathBuf>,
-    #[arg(short, long, help = "Won't emit any output")]
-    quiet: bool,
-    #[arg(short, long, help = "Formats the entire package using the Cargo.toml")]
-    package: bool,
-    #[arg(long, help = "Limits threads to specified count when using --package")]
-    thread_count: Option<usize>,
-    #[arg(short, long, default_value_t = FormatConfig::default().max_width)]
-    line_length: usize,
-    #[arg(long, help = "For any node that's split, all parent nodes must also be split")]
-    root_splits: bool,
-    #[arg(
-        long,
-        help = "Always split {} groups with >= this number of children; disable with `off`",
-        default_value_t = match FormatConfig::default().split_brace_threshold {
-            Some(x) => Offable::On(x),
-            None => Offable::Off,
-        },
-    )]
-    split_brace_threshold: Offable<usize>,
-    #[arg(
-        long,
-        help = "Always split #[] attributes; disable with `off`",
-        default_value_t = match FormatConfig::default().split_attributes {
-            true => Offable::On(On),
-            false => Offable::Off,
-        },
-    )]
-    split_attributes: Offable<On>,
-    #[arg(
-        long,
-        help = "Always split where clauses; disable with `off`",
-        default_value_t = match FormatConfig::default().split_attributes {
-            true => Offable::On(On),
-            false => Offable::Off,
-        },
-    )]
-    split_where: Offable<On>,
-    #[arg(
-        long,
-        help = "Use a max comment length relative to start of comment (i.e. ignoring indentation); disable with `off`",
-        default_value_t = match FormatConfig::default().comment_width {
-            Some(x) => Offable::On(x),
-            None => Offable::Off,
-        },
-    )]
-    comment_length: Offable<usize>,
-    #[arg(
-        long,
-        help = "Problems formatting comments are fatal; disable with `false`",
-        default_value_t = match FormatConfig::default().comment_errors_fatal {
-            true => Offable::On(On),
-            false => Offable::Off,
-        },
-    )]
-    comment_errors_fatal: Offable<On>,
+/// Lists `.rs` files that differ from `git_ref` in the working tree, plus untracked
+/// ones.
+fn changed_files(git_ref: &str, excludes: &Excludes) -> Result<Vec<PathBuf>> {
+    let root = git_root()?;
+    let mut seen_files = HashSet::new();
+    let mut files = vec![];
+    let changed = git(&root, &["diff", "--name-only", "--diff-filter=d", "-z", git_ref, "--"], None)?;
+    add_git_rs_files(&root, &changed, excludes, &mut seen_files, &mut files);
+    let untracked = git(&root, &["ls-files", "--others", "--exclude-standard", "-z"], None)?;
+    add_git_rs_files(&root, &untracked, excludes, &mut seen_files, &mut files);
+    Ok(files)
 }
 
-fn skip(src: &str) -> bool {
-    src.lines().take(5).any(|l| l.contains("`nogenemichaels`"))
+/// Lists staged `.rs` files, returning the repository root along with them.
+fn staged_files(excludes: &Excludes) -> Result<(PathBuf, Vec<PathBuf>)> {
+    let root = git_root()?;
+    let mut seen_files = HashSet::new();
+    let mut files = vec![];
+    let staged = git(&root, &["diff", "--cached", "--name-only", "--diff-filter=d", "-z", "--"], None)?;
+    add_git_rs_files(&root, &staged, excludes, &mut seen_files, &mut files);
+    Ok((root, files))
 }
 
-fn process(config: &FormatConfig, source: &str) -> Result<String> {
-    let res = format_str(source, config)?;
-    if !res.lost_comments.is_empty() {
-        return Err(
-            anyhow!(
-                "The following comments were missed during formatting: {:?}",
-                res.lost_comments.values().flatten().collect::<Vec<&Comment>>()
-            ),
-        );
+/// Formats the staged version of a file and stages the result. The working tree copy
+/// is only updated if it has no unstaged changes, so partially staged files keep
+/// their unstaged parts.
+fn process_staged_file(
+    config: &FormatConfig,
+    cache: Option<&FormatCache>,
+    index_lock: &Mutex<()>,
+    root: &Path,
+    file_path: &Path,
+) -> Result<FileOutcome> {
+    let rel_path = file_path.strip_prefix(root)?.to_string_lossy().into_owned();
+    let entry = String::from_utf8(git(root, &["ls-files", "--stage", "-z", "--", &rel_path], None)?)?;
+    let (meta, _) = entry.split_once('\t').ok_or_else(|| anyhow!("Not in the index"))?;
+    let mut meta = meta.split(' ');
+    let mode = meta.next().unwrap_or_default();
+    let object = meta.next().unwrap_or_default();
+    if meta.next() != Some("0") {
+        return Err(anyhow!("Index has unresolved conflicts"));
     }
-    match syn::parse_str::<File>(&res.rendered) {
-        Ok(_) => { },
-        Err(e) => {
-            return Err(
-                anyhow!(
-                    "Rendered document couldn't be re-parsed in verification step at {}:{}: {}\n\n{}",
-                    e.span().start().line,
-                    e.span().start().column,
-                    e,
-                    res
-                        .rendered
-                        .lines()
-                        .enumerate()
-                        .skip(e.span().start().line.saturating_sub(5))
-                        .take(10)
-                        .map(|(ln, l)| format!("{:0>4} {}", ln + 1, l))
-                        .collect::<Vec<String>>()
-                        .join("\n")
-                ),
-            );
-        },
-    };
-    Ok(res.rendered)
-}
-
-fn process_workspace_file(config: FormatConfig, file_path: PathBuf) {
-    let res = || -> Result<()> {
-        let source = fs::read_to_string(file_path.clone())?;
-        if skip(&source) {
-            print_skipping_text();
-            eprintln!("{}", &file_path.to_string_lossy());
-            return Result::Ok(());
+    if mode != "100644" && mode != "100755" {
+        // Symlinks, submodules
+        return Ok(FileOutcome::Skipped);
+    }
+    let staged = git(root, &["cat-file", "blob", object], None)?;
+    let source = String::from_utf8(staged.clone())?;
+    if skip(&source) {
+        return Ok(FileOutcome::Skipped);
+    }
+    if let Some(cache) = cache {
+        if cache.is_formatted(&source) {
+            return Ok(FileOutcome::Unchanged);
+        }
+    }
+    let out = process(config, file_path, &source)?;
+    if out != source {
+        let new_object =
+            String::from_utf8(
+                git(root, &["hash-object", "-w", "--no-filters", "--stdin"], Some(out.as_bytes()))?,
+            )?;
+        {
+            // Concurrent updates would fail on git's index lock
+            let _index = index_lock.lock().unwrap();
+            git(
+                root,
+                &["update-index", "--cacheinfo", &format!("{},{},{}", mode, new_object.trim(), rel_path)],
+                None,
+            )?;
+        }
+        if fs::read(file_path).ok().as_ref() == Some(&staged) {
+            fs::write(file_path, out.as_bytes())?;
         }
-        fs::write(&file_path, process(&config, &source)?.as_bytes())?;
-        Ok(())
-    };
-    match res() {
-        Ok(_) => {
-            eprintln!("\x1B[1;32m   Formatted\x1B[0;22m {}", file_path.as_path().to_string_lossy());
-        },
-        Err(e) => {
-            print_error_text();
-            eprintln!("formatting file {}: {:?}", file_path.to_string_lossy(), e);
-        },
     }
+    if let Some(cache) = cache {
+        cache.mark_formatted(&out);
+    }
+    Ok(FileOutcome::Formatted)
 }
 
 fn main() {
     let args = Args::parse();
-    let config = FormatConfig {
-        quiet: args.quiet,
-        max_width: args.line_length,
-        root_splits: args.root_splits,
-        split_brace_threshold: match args.split_brace_threshold {
-            Offable::Off => None,
-            Offable::On(n) => Some(n),
-        },
-        split_attributes: match args.split_attributes {
-            Offable::Off => false,
-            Offable::On(_) => true,
-        },
-        split_where: match args.split_where {
-            Offable::Off => false,
-            Offable::On(_) => true,
-        },
-        comment_width: match args.comment_length {
-            Offable::Off => None,
-            Offable::On(n) => Some(n),
+    let config = args.format.config();
+    let output = match args.files_args.output(args.check) {
+        Ok(o) => o,
+        Err(e) => {
+            print_error_text();
+            eprintln!("preparing output: {:?}", e);
+            process::exit(1);
         },
-        comment_errors_fatal: match args.comment_errors_fatal {
-            Offable::Off => false,
-            Offable::On(_) => true,
+    };
+    let excludes = match args.files_args.excludes() {
+        Ok(e) => e,
+        Err(e) => {
+            print_error_text();
+            eprintln!("loading excludes: {:?}", e);
+            process::exit(1);
         },
     };
-    if args.package {
-        let res = || -> Result<()> {
-            {
-                let inst = time::Instant::now();
-                eprintln!("\x1B[1;32m  Formatting\x1B[0;22m workspace...");
-                let mut project_cargo_toml = None;
-                let c_dir = current_dir()?;
-                let mut at: Option<&Path> = Some(&c_dir);
-                while let Some(d) = at.take() {
-                    let cargo_toml_path = d.join(CARGO_TOML);
-                    if cargo_toml_path.exists() && cargo_manifest::Manifest::from_path(&cargo_toml_path).is_ok() {
-                        project_cargo_toml = Some(cargo_toml_path);
-                        break;
-                    }
-                    at = d.parent();
-                }
-                let wct = project_cargo_toml.ok_or_else(|| anyhow::anyhow!("No Cargo.toml found!"))?;
-                let manifest = cargo_manifest::Manifest::from_path(&wct)?;
-                process_cargo_toml(
-                    wct.parent().expect("Unable to get parent of Cargo.toml").to_path_buf(),
-                    manifest,
-                    args.thread_count,
-                    config,
-                )?;
-                eprintln!(
-                    "\x1B[1;32m    Finished\x1B[0;22m workspace formatting successfully in {:.2}s",
-                    time::Instant::now().duration_since(inst).as_secs_f64()
-                );
-                Result::Ok(())
-            }
+    if let Some(Command::Watch { files }) = &args.command {
+        let res = if args.check || args.files_args.output_dir.is_some() || args.files_args.atomic {
+            Err(anyhow!("--check, --output-dir and --atomic can't be used with watch"))
+        } else if files.is_empty() {
+            select_packages(
+                &args,
+            ).and_then(
+                |(_, packages)| watch(
+                    &config,
+                    &packages.iter().map(|p| p.dir.clone()).collect::<Vec<_>>(),
+                    || Ok(package_files(&packages, args.files_args.follow_mods, &excludes)),
+                ),
+            )
+        } else {
+            let roots = files.iter().filter(|f| f.is_dir()).cloned().collect::<Vec<_>>();
+            watch(&config, &roots, || Ok(expand_files(files, &excludes)))
+        };
+        if let Err(e) = res {
+            print_error_text();
+            eprintln!("watching: {:?}", e);
+            process::exit(1);
+        }
+    } else if args.package.is_some() {
+        let res = || -> Result<bool> {
+            let (manifest_path, packages) = select_packages(&args)?;
+            let failed =
+                process_packages(&args.files_args, &manifest_path, &packages, &excludes, config, output.clone());
+            output.finish(args.format.quiet, failed)?;
+            Result::Ok(failed)
         };
         match res() {
-            Ok(_) => { },
+            Ok(failed) => {
+                if failed {
+                    process::exit(1);
+                }
+            },
             Err(e) => {
                 print_error_text();
                 eprintln!("formatting: {:?}", e);
                 process::exit(1);
             },
         };
+    } else if let Some(git_ref) = &args.changed {
+        let inst = time::Instant::now();
+        let files = match changed_files(git_ref, &excludes) {
+            Ok(f) => f,
+            Err(e) => {
+                print_error_text();
+                eprintln!("listing changed files: {:?}", e);
+                process::exit(1);
+            },
+        };
+        let cache = args.files_args.cache(&config, None);
+        let job_output = output.clone();
+        let failed =
+            process_files(
+                files,
+                args.files_args.thread_count,
+                config,
+                move |f| process_file(&config, cache.as_ref(), &job_output, f),
+            );
+        finish_files(inst, &output, args.format.quiet, failed);
+    } else if args.staged {
+        let inst = time::Instant::now();
+        let (root, files) = match staged_files(&excludes) {
+            Ok(f) => f,
+            Err(e) => {
+                print_error_text();
+                eprintln!("listing staged files: {:?}", e);
+                process::exit(1);
+            },
+        };
+        let cache = args.files_args.cache(&config, None);
+        let index_lock = Mutex::new(());
+        let failed =
+            process_files(
+                files,
+                args.files_args.thread_count,
+                config,
+                move |f| process_staged_file(&config, cache.as_ref(), &index_lock, &root, f),
+            );
+        finish_files(inst, &Output::InPlace, args.format.quiet, failed);
     } else if args.files.is_empty() {
         let inst = time::Instant::now();
-        let res = || -> Result<()> {
+        let label = match &args.stdin_filepath {
+            Some(file_path) => file_path.to_string_lossy().to_string(),
+            None => "stdin".to_string(),
+        };
+        let res = || -> Result<bool> {
+            if args.files_args.output_dir.is_some() {
+                return Err(anyhow!("--output-dir needs files or --package to format"));
+            }
             let mut source = Vec::new();
             std::io::stdin().read_to_end(&mut source)?;
             let source = String::from_utf8(source)?;
-            if skip(&source) {
-                print!("{}", source);
-                anyhow::Ok(())
+            let ignored = match &args.stdin_filepath {
+                Some(file_path) => {
+                    let file_path = current_dir()?.join(file_path);
+                    let dir = file_path.parent().unwrap_or(&file_path);
+                    is_ignored_file(&args.files_args.excludes_from(dir)?, &file_path)
+                },
+                None => false,
+            };
+            if ignored || skip(&source) {
+                if !args.check {
+                    print!("{}", source);
+                }
+                anyhow::Ok(true)
             } else {
-                let out = process(&config, &source)?;
-                print!("{}", out);
-                anyhow::Ok(())
+                let out = match &args.stdin_filepath {
+                    Some(file_path) => process_path(&config, file_path, &source)?,
+                    None => process(&config, Path::new("stdin"), &source)?,
+                };
+                if !args.check {
+                    print!("{}", out);
+                }
+                anyhow::Ok(!args.check || out == source)
             }
         };
-        match res() {
-            Ok(_) => { },
+        let failed = match res() {
+            Ok(formatted) => {
+                if !formatted && !args.format.quiet {
+                    eprintln!("\x1B[1;33m Unformatted\x1B[0;22m {}", label);
+                }
+                !formatted
+            },
             Err(e) => {
-                if !args.quiet {
+                if !args.format.quiet {
                     print_error_text();
-                    eprintln!("formatting stdin: {:?}", e);
+                    eprintln!("formatting {}: {:?}", label, e);
                 }
-                process::exit(1);
+                true
             },
         };
-        eprintln!(
-            "\x1B[1;32m    Finished\x1B[0;22m workspace formatted successfully in {:.2}s",
-            time::Instant::now().duration_since(inst).as_secs_f64()
-        );
-    } else {
-        let inst = time::Instant::now();
-        let mut failed = false;
-        for file in &args.files {
-            let res = || -> Result<()> {
-                let source = String::from_utf8(fs::read(file)?)?;
-                if skip(&source) {
-                    print_skipping_text();
-                    eprintln!("{}", &file.to_string_lossy());
-                    return Ok(());
-                }
-                if !args.quiet {
-                    eprintln!("\x1B[1;32m  Formatting\x1B[0;22m {}", &file.to_string_lossy());
-                };
-                let out = process(&config, &source)?;
-                fs::write(file, out.as_bytes())?;
-                Ok(())
-            };
-            match res() {
-                Ok(_) => { },
-                Err(e) => {
-                    if !args.quiet {
-                        print_error_text();
-                        eprintln!("formatting {}: {:?}", &file.to_string_lossy(), e);
-                    }
-                    failed = true;
-                },
-            };
-        }
-        eprintln!(
-            "\x1B[1;32m    Finished\x1B[0;22m workspace formatted successfully in {:.2}s",
-            time::Instant::now().duration_since(inst).as_secs_f64()
-        );
+        print_finished(inst, args.format.quiet, failed);
         if failed {
             process::exit(1);
         }
+    } else {
+        let inst = time::Instant::now();
+        let files = expand_files(&args.files, &excludes);
+        let cache = args.files_args.cache(&config, None);
+        let job_output = output.clone();
+        let failed =
+            process_files(
+                files,
+                args.files_args.thread_count,
+                config,
+                move |f| process_file(&config, cache.as_ref(), &job_output, f),
+            );
+        finish_files(inst, &output, args.format.quiet, failed);
     }
 }
 
-fn process_dirs(path: PathBuf, manifest: Manifest) -> Result<HashSet<PathBuf>> {
-    let mut dirs = HashSet::from([]);
-    for bin in manifest.bin.into_iter().flatten() {
-        if let Some(bin_path) = bin.path {
-            dirs.insert(path.join(bin_path).parent().unwrap().to_owned());
-        }
-    }
-    if let Some(lib) = manifest.lib {
-        if let Some(p) =
-            lib
-                .path
-                .and_then(|p| p.parse::<PathBuf>().ok())
-                .and_then(|p| p.parent().map(|pp| pp.to_path_buf())) {
-            dirs.insert(p);
-        }
-    }
-    for bench in manifest.bench.into_iter().flatten() {
-        if let Some(bench_path) = bench.path {
-            dirs.insert(path.join(bench_path).parent().unwrap().to_owned());
-        }
-    }
-    for test in manifest.test.into_iter().flatten() {
-        if let Some(test_path) = test.path {
-            dirs.insert(path.join(test_path).parent().unwrap().to_owned());
-        }
-    }
-    for example in manifest.example.into_iter().flatten() {
-        if let Some(example_path) = example.path {
-            dirs.insert(path.join(example_path).parent().unwrap().to_owned());
-        }
-    }
-    if let Some(ws) = manifest.workspace {
-        let workspace_dirs: Vec<PathBuf> =
-            ws.members.into_iter().filter_map(|m| path.join(&m).exists().then(|| path.join(m))).collect();
+/// Which packages `--package` picks.
+enum PackageSelection<'a> {
+    /// The package at the manifest, plus all workspace members if it's the workspace root
+    Manifest,
+    /// The workspace members with these names
+    Named(&'a [String]),
+}
 
-        // loop through each folder in the workspace and recursively run the formatter
-        for workspace in workspace_dirs {
-            let manifest = cargo_manifest::Manifest::from_path(workspace.join(CARGO_TOML))?;
-            // this should work with the recursion
-            dirs.extend(process_dirs(workspace, manifest)?);
-        }
+/// Finds the packages to format, returning the manifest used along with them.
+fn select_packages(args: &Args) -> Result<(PathBuf, Vec<PackageInfo>)> {
+    let selection = match args.package.as_deref() {
+        Some(names) if !names.is_empty() => PackageSelection::Named(names),
+        _ => PackageSelection::Manifest,
     };
-
-    // default bins location
-    if path.join("bin").exists() {
-        dirs.insert(path.join("bin"));
-    }
-
-    // default benches location
-    if path.join("benches").exists() {
-        dirs.insert(path.join("benches"));
-    }
-
-    // default tests location
-    if path.join("tests").exists() {
-        dirs.insert(path.join("tests"));
-    }
-
-    // default examples location
-    if path.join("examples").exists() {
-        dirs.insert(path.join("examples"));
+    let manifest_path = args.files_args.manifest_path.as_deref();
+    match selection {
+        PackageSelection::Manifest => {
+            let workspace = Workspace::load(manifest_path, false)?;
+            let is_workspace_root = workspace.manifest.workspace.is_some();
+            Ok(workspace.at_manifest(is_workspace_root))
+        },
+        PackageSelection::Named(names) => Workspace::load(manifest_path, true)?.named(names),
     }
-
-    // add src if exists
-    if path.join("src").exists() {
-        dirs.insert(path.join("src"));
-    };
-    Ok(dirs)
 }
 
-fn process_cargo_toml(
-    path: PathBuf,
-    manifest: Manifest,
-    thread_count: Option<usize>,
-    config: FormatConfig,
-) -> Result<()> {
-    let dirs = process_dirs(path.clone(), manifest)?;
-
-    // solves the situation where if the bin/ is inide src/ file doesn't get formatted
-    // twice, open to better solution
-    let mut formatted_files = HashSet::new();
-    for dir in dirs {
-        let pool = if let Some(t) = thread_count {
-            threadpool::Builder::new().num_threads(t)
-        } else {
-            threadpool::Builder::new()
-        }.build();
-        let current_working_folder = path.join(dir);
-        for f in walkdir::WalkDir::new(&current_working_folder) {
-            match f {
-                Ok(file) => {
-                    let file_path = file.path().to_path_buf();
-                    if !formatted_files.contains(&file_path) && file_path.extension() == Some(OsStr::new("rs")) {
-                        formatted_files.insert(file_path);
-                        pool.execute(move || {
-                            process_workspace_file(config, file.path().to_path_buf())
-                        });
-                    }
-                },
-                Err(e) => {
-                    eprintln!("Error opening file {}: {}", current_working_folder.to_string_lossy(), e);
-                    continue;
-                },
-            }
-        }
-        pool.join();
+fn finish_files(inst: time::Instant, output: &Output, quiet: bool, failed: bool) {
+    if let Err(e) = output.finish(quiet, failed) {
+        print_error_text();
+        eprintln!("writing: {:?}", e);
+        process::exit(1);
+    }
+    print_finished(inst, quiet, failed);
+    if failed {
+        process::exit(1);
     }
-    Ok(())
 }
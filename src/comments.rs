@@ -2,27 +2,39 @@ use anyhow::{
     anyhow,
     Result,
 };
-use markdown::mdast::Node;
+use markdown::mdast::{
+    AlignKind,
+    Link,
+    ListItem,
+    Node,
+};
 use crate::{
     Comment,
     CommentMode,
+    ExtractedComment,
+    CommentStyle,
+    FormatConfig,
     es,
+    snippets::{
+        format_doc_example,
+        is_commented_code,
+        is_rust_block,
+    },
 };
 use proc_macro2::{
     LineColumn,
     TokenStream,
     Group,
 };
-use std::cell::RefCell;
 use std::collections::{
     HashMap,
 };
 use std::hash::Hash;
-use std::rc::Rc;
 use std::str::FromStr;
 use structre::UnicodeRegex;
+use unicode_width::UnicodeWidthStr;
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub struct HashLineColumn(pub LineColumn);
 
 impl Hash for HashLineColumn {
@@ -32,6 +44,52 @@ impl Hash for HashLineColumn {
 }
 
 pub fn extract_comments(source: &str) -> Result<(HashMap<HashLineColumn, Vec<Comment>>, TokenStream)> {
+    let (comments, tokens) = extract_comments_with(source, &FormatConfig::default())?;
+    Ok((comments.into_iter().map(|(k, v)| (k, v.into_iter().map(|c| c.comment).collect())).collect(), tokens))
+}
+
+/// Whether the text after the last token on a line is only comments, none of which
+/// continue onto the next line. With `block_only`, `//` comments don't count.
+fn only_comments(mut text: &str, block_only: bool) -> bool {
+    loop {
+        text = text.trim_start();
+        if text.starts_with("//") {
+            return !block_only;
+        }
+        if !text.starts_with("/*") {
+            return false;
+        }
+        let mut nesting = 0;
+        let mut i = 0;
+        loop {
+            if text[i..].starts_with("/*") {
+                nesting += 1;
+                i += 2;
+            } else if text[i..].starts_with("*/") {
+                nesting -= 1;
+                i += 2;
+                if nesting == 0 {
+                    break;
+                }
+            } else if let Some(c) = text[i..].chars().next() {
+                i += c.len_utf8();
+            } else {
+                return false;
+            }
+        }
+        text = &text[i..];
+        if text.trim().is_empty() {
+            return true;
+        }
+    }
+}
+
+/// Like `extract_comments`, with config options that affect how comments are
+/// associated, and keeping how each comment was written.
+pub(crate) fn extract_comments_with(
+    source: &str,
+    config: &FormatConfig,
+) -> Result<(HashMap<HashLineColumn, Vec<ExtractedComment>>, TokenStream)> {
     let mut line_lookup = vec![];
     {
         let mut offset = 0usize;
@@ -48,9 +106,10 @@ pub fn extract_comments(source: &str) -> Result<(HashMap<HashLineColumn, Vec<Com
 
     struct State<'a> {
         source: &'a str,
+        config: &'a FormatConfig,
         // starting offset of each line
         line_lookup: Vec<usize>,
-        comments: HashMap<HashLineColumn, Vec<Comment>>,
+        comments: HashMap<HashLineColumn, Vec<ExtractedComment>>,
         line_start: Option<LineColumn>,
         last_offset: usize,
         start_re: Option<UnicodeRegex>,
@@ -67,7 +126,9 @@ pub fn extract_comments(source: &str) -> Result<(HashMap<HashLineColumn, Vec<Com
                 self.source[line_start_offset..].chars().take(loc.column).map(char::len_utf8).sum::<usize>()
         }
 
-        fn add_comments(&mut self, end: LineColumn, whole_text: &str) {
+        // `line_end` is set for the comments after the last token on a line, which aren't followed
+        // by a token on the same line
+        fn add_comments(&mut self, end: LineColumn, whole_text: &str, line_end: bool, trailing: bool) {
             let start_re =
                 &self
                     .start_re
@@ -76,10 +137,12 @@ pub fn extract_comments(source: &str) -> Result<(HashMap<HashLineColumn, Vec<Com
                 &self.block_event_re.get_or_insert_with(|| UnicodeRegex::new(r#"((?:/\*)|(?:\*/))"#).unwrap());
 
             struct CommentBuffer {
-                out: Vec<Comment>,
+                out: Vec<ExtractedComment>,
                 mode: CommentMode,
+                style: CommentStyle,
                 lines: Vec<String>,
                 loc: LineColumn,
+                trailing: bool,
             }
 
             impl CommentBuffer {
@@ -87,18 +150,24 @@ pub fn extract_comments(source: &str) -> Result<(HashMap<HashLineColumn, Vec<Com
                     if self.lines.is_empty() {
                         return;
                     }
-                    self.out.push(Comment {
-                        loc: self.loc,
-                        mode: self.mode,
-                        lines: self.lines.split_off(0).join("\n"),
+                    self.out.push(ExtractedComment {
+                        comment: Comment {
+                            loc: self.loc,
+                            mode: self.mode,
+                            lines: self.lines.split_off(0).join("\n"),
+                        },
+                        style: self.style,
+                        trailing: self.trailing,
+                        inline: false,
                     });
                 }
 
-                fn add(&mut self, mode: CommentMode, line: &str) {
-                    if self.mode != mode && !self.lines.is_empty() {
+                fn add(&mut self, mode: CommentMode, style: CommentStyle, line: &str) {
+                    if (self.mode != mode || self.style != style) && !self.lines.is_empty() {
                         self.flush();
                     }
                     self.mode = mode;
+                    self.style = style;
                     self.lines.push(line.to_string());
                 }
             }
@@ -106,8 +175,10 @@ pub fn extract_comments(source: &str) -> Result<(HashMap<HashLineColumn, Vec<Com
             let mut buffer = CommentBuffer {
                 out: vec![],
                 mode: CommentMode::Normal,
+                style: CommentStyle::Line,
                 lines: vec![],
                 loc: end,
+                trailing,
             };
             let mut text = whole_text;
             'comment_loop : loop {
@@ -134,7 +205,7 @@ pub fn extract_comments(source: &str) -> Result<(HashMap<HashLineColumn, Vec<Com
                                     Some(line_end) => (&text[..line_end], line_end + 1),
                                     None => (text, text.len()),
                                 };
-                                buffer.add(mode, line);
+                                buffer.add(mode, CommentStyle::Line, line);
                                 text = &text[next_start..];
                             },
                             "/*" => {
@@ -171,12 +242,72 @@ pub fn extract_comments(source: &str) -> Result<(HashMap<HashLineColumn, Vec<Com
                                         _ => unreachable!(),
                                     }
                                 };
+                                text = &text[next_start..];
+
+                                // Single line comments followed by code on the same line stay inline
+                                let single_line = !lines.contains('\n') && !text.contains('\n');
+                                if mode == CommentMode::Normal && single_line && !line_end && end.line != 0 {
+                                    buffer.flush();
+                                    buffer.out.push(ExtractedComment {
+                                        comment: Comment {
+                                            loc: end,
+                                            mode,
+                                            lines: lines.trim().to_string(),
+                                        },
+                                        style: CommentStyle::Block,
+                                        trailing: false,
+                                        inline: true,
+                                    });
+                                    continue;
+                                }
+
+                                // Each block comment is separate, even if next to another one
+                                buffer.flush();
+
+                                // Commented-out code keeps its lines and their relative indentation
+                                let detect = mode == CommentMode::Normal && self.config.detect_commented_code;
+                                if detect && is_commented_code(lines) {
+                                    let mut code_lines = lines.lines().collect::<Vec<_>>();
+
+                                    // Code on the `/*` line doesn't share the indentation of the lines after it
+                                    let opener_line = match code_lines.first() {
+                                        Some(l) if !l.trim().is_empty() => Some(code_lines.remove(0).trim()),
+                                        _ => None,
+                                    };
+                                    while opener_line.is_none() &&
+                                        code_lines.first().map(|l| l.trim().is_empty()).unwrap_or(false) {
+                                        code_lines.remove(0);
+                                    }
+                                    while code_lines.last().map(|l| l.trim().is_empty()).unwrap_or(false) {
+                                        code_lines.pop();
+                                    }
+                                    let indent =
+                                        code_lines
+                                            .iter()
+                                            .filter(|l| !l.trim().is_empty())
+                                            .map(|l| l.len() - l.trim_start().len())
+                                            .min()
+                                            .unwrap_or(0);
+                                    if let Some(line) = opener_line {
+                                        buffer.add(CommentMode::Verbatim, CommentStyle::Block, &format!(" {}", line));
+                                    }
+                                    for line in code_lines {
+                                        let line = line.get(indent..).unwrap_or("").trim_end();
+                                        buffer.add(CommentMode::Verbatim, CommentStyle::Block, &format!(" {}", line));
+                                    }
+                                    buffer.flush();
+                                    continue;
+                                }
                                 for line in lines.lines() {
                                     let mut line = line.trim();
-                                    line = line.strip_prefix("* ").unwrap_or(line);
-                                    buffer.add(mode, line);
+                                    line = if line == "*" {
+                                        ""
+                                    } else {
+                                        line.strip_prefix("* ").unwrap_or(line)
+                                    };
+                                    buffer.add(mode, CommentStyle::Block, line);
                                 }
-                                text = &text[next_start..];
+                                buffer.flush();
                             },
                             _ => unreachable!(),
                         }
@@ -187,13 +318,34 @@ pub fn extract_comments(source: &str) -> Result<(HashMap<HashLineColumn, Vec<Com
                 }
             }
             buffer.flush();
+
+            // Commented-out code would be mangled by markdown formatting
+            if self.config.detect_commented_code {
+                for c in &mut buffer.out {
+                    if c.comment.mode == CommentMode::Normal && c.style == CommentStyle::Line &&
+                        is_commented_code(&c.comment.lines) {
+                        c.comment.mode = CommentMode::Verbatim;
+                    }
+                }
+            }
             if !buffer.out.is_empty() {
-                self.comments.insert(HashLineColumn(end), buffer.out);
+                self.comments.entry(HashLineColumn(end)).or_default().extend(buffer.out);
             }
         }
 
+        // Whether a line-end comment should stay where it is rather than being moved to the start
+        // of the line
+        fn keep_trailing(&self, eol: usize) -> bool {
+            if !self.config.keep_trailing_comments {
+                return false;
+            }
+            let line_start = self.source[..eol].rfind('\n').map(|i| i + 1).unwrap_or(0);
+            self.source[line_start .. eol].trim_end().chars().count() <= self.config.max_width
+        }
+
         fn extract(&mut self, mut start: usize, end: LineColumn) {
-            // Transpose line-end comments to line-start
+            // Transpose line-end comments to line-start, or keep them at the end of the line (attached to
+            // the next token) if configured
             if if let Some(previous_start) = &self.line_start {
                 if end.line > previous_start.line {
                     let eol = match self.source[start..].find('\n') {
@@ -201,10 +353,19 @@ pub fn extract_comments(source: &str) -> Result<(HashMap<HashLineColumn, Vec<Com
                         None => self.source.len(),
                     };
                     let text = &self.source[start .. eol];
-                    if text.trim_start().starts_with("//") {
-                        self.add_comments(*previous_start, text);
+                    let end_offset = self.to_offset(end);
+                    if only_comments(text, true) && self.source[end_offset..].starts_with([')', ']', '}']) {
+                        // Inline comments after the last element of a split list stay with the bracket
+                        self.add_comments(end, text, false, false);
+                        start = eol;
+                    } else if only_comments(text, false) {
+                        if self.keep_trailing(eol) {
+                            self.add_comments(end, text, true, true);
+                        } else {
+                            self.add_comments(*previous_start, text, true, false);
+                        }
+                        start = eol;
                     }
-                    start = eol;
                     true
                 } else {
                     false
@@ -221,13 +382,14 @@ pub fn extract_comments(source: &str) -> Result<(HashMap<HashLineColumn, Vec<Com
                 return;
             }
             let whole_text = &self.source[start .. end_offset];
-            self.add_comments(end, whole_text);
+            self.add_comments(end, whole_text, false, false);
         }
     }
 
     // Extract comments
     let mut state = State {
         source,
+        config,
         line_lookup,
         comments: HashMap::new(),
         last_offset: 0usize,
@@ -298,16 +460,29 @@ pub fn extract_comments(source: &str) -> Result<(HashMap<HashLineColumn, Vec<Com
                 ),
             )?,
         );
-    state.add_comments(LineColumn {
+    let eof_loc = LineColumn {
         line: 0,
         column: 1,
-    }, &source[state.last_offset..]);
+    };
+    let mut start = state.last_offset;
+    let eol = source[start..].find('\n').map(|n| start + n).unwrap_or(source.len());
+    if state.line_start.is_some() && only_comments(&source[start .. eol], false) && state.keep_trailing(eol) {
+        state.add_comments(eof_loc, &source[start .. eol], true, true);
+        start = eol;
+    }
+    state.add_comments(eof_loc, &source[start..], false, false);
     Ok((state.comments, tokens))
 }
 
-struct State {
+struct State<'a> {
+    // The markdown being formatted, for writing parts verbatim
+    source: &'a str,
+    // Set to format Rust code blocks
+    code_config: Option<&'a FormatConfig>,
     line_buffer: String,
     need_nl: bool,
+    // Backing storage for `LineState`s
+    line_states: Vec<LineState_>,
 }
 
 #[derive(Debug)]
@@ -320,29 +495,6 @@ struct LineState_ {
 }
 
 impl LineState_ {
-    fn flush_always(&mut self, state: &mut State, out: &mut String, wrapping: bool) {
-        out.push_str(format!("{}{}{}{}", if state.need_nl {
-            "\n"
-        } else {
-            ""
-        }, match &self.first_prefix.take() {
-            Some(t) => t,
-            None => &*self.prefix,
-        }, &state.line_buffer, if wrapping && self.explicit_wrap {
-            " \\"
-        } else {
-            ""
-        }).trim_end());
-        state.line_buffer.clear();
-        state.need_nl = true;
-    }
-
-    fn flush(&mut self, state: &mut State, out: &mut String, wrapping: bool) {
-        if !state.line_buffer.trim().is_empty() {
-            self.flush_always(state, out, wrapping);
-        }
-    }
-
     fn calc_max_width(&self) -> usize {
         match self.rel_max_width {
             Some(w) => self.prefix.len() + w,
@@ -363,59 +515,90 @@ fn get_splits(text: &str) -> impl Iterator<Item = usize> + '_ {
     text.char_indices().filter(|i| i.1 == ' ').map(|i| i.0 + 1)
 }
 
-struct LineState(Rc<RefCell<LineState_>>);
+#[derive(Clone, Copy)]
+struct LineState(usize);
 
 impl LineState {
     fn new(
+        state: &mut State,
         first_prefix: Option<String>,
         prefix: String,
         max_width: usize,
         rel_max_width: Option<usize>,
         explicit_wrap: bool,
     ) -> LineState {
-        LineState(Rc::new(RefCell::new(LineState_ {
+        let idx = LineState(state.line_states.len());
+        state.line_states.push(LineState_ {
             first_prefix,
             prefix,
             explicit_wrap,
             max_width,
             rel_max_width,
-        })))
+        });
+        idx
     }
 
     fn clone_inline(&self) -> LineState {
-        LineState(self.0.clone())
-    }
-
-    fn clone_zero_indent(&self) -> LineState {
-        let mut s = self.0.as_ref().borrow_mut();
-        LineState(Rc::new(RefCell::new(LineState_ {
-            first_prefix: s.first_prefix.take(),
-            prefix: s.prefix.clone(),
-            explicit_wrap: s.explicit_wrap,
-            max_width: s.max_width,
-            rel_max_width: s.rel_max_width,
-        })))
-    }
-
-    fn clone_indent(&self, first_prefix: Option<String>, prefix: String, explicit_wrap: bool) -> LineState {
-        let mut s = self.0.as_ref().borrow_mut();
-        LineState(Rc::new(RefCell::new(LineState_ {
-            first_prefix: match (s.first_prefix.take(), first_prefix) {
-                (None, None) => None,
-                (None, Some(p)) => Some(format!("{}{}", s.prefix, p)),
-                (Some(p), None) => Some(p),
-                (Some(p1), Some(p2)) => Some(format!("{}{}", p1, p2)),
-            },
-            prefix: format!("{}{}", s.prefix, prefix),
-            explicit_wrap: s.explicit_wrap || explicit_wrap,
-            max_width: s.max_width,
-            rel_max_width: s.rel_max_width,
-        })))
+        LineState(self.0)
+    }
+
+    fn clone_zero_indent(&self, state: &mut State) -> LineState {
+        let s = state.line_states.get_mut(self.0).unwrap();
+        let first_prefix = s.first_prefix.take();
+        let prefix = s.prefix.clone();
+        let explicit_wrap = s.explicit_wrap;
+        let max_width = s.max_width;
+        let rel_max_width = s.rel_max_width;
+        LineState::new(state, first_prefix, prefix, max_width, rel_max_width, explicit_wrap)
+    }
+
+    fn clone_indent(
+        &self,
+        state: &mut State,
+        first_prefix: Option<String>,
+        prefix: String,
+        explicit_wrap: bool,
+    ) -> LineState {
+        let s = state.line_states.get_mut(self.0).unwrap();
+        let first_prefix = match (s.first_prefix.take(), first_prefix) {
+            (None, None) => None,
+            (None, Some(p)) => Some(format!("{}{}", s.prefix, p)),
+            (Some(p), None) => Some(p),
+            (Some(p1), Some(p2)) => Some(format!("{}{}", p1, p2)),
+        };
+        let prefix = format!("{}{}", s.prefix, prefix);
+        let explicit_wrap = s.explicit_wrap || explicit_wrap;
+        let max_width = s.max_width;
+        let rel_max_width = s.rel_max_width;
+        LineState::new(state, first_prefix, prefix, max_width, rel_max_width, explicit_wrap)
+    }
+
+    fn flush_line(&self, state: &mut State, out: &mut String, wrapping: bool) {
+        let s = state.line_states.get_mut(self.0).unwrap();
+        out.push_str(format!("{}{}{}{}", if state.need_nl {
+            "\n"
+        } else {
+            ""
+        }, match &s.first_prefix.take() {
+            Some(t) => t,
+            None => &*s.prefix,
+        }, &state.line_buffer, if wrapping && s.explicit_wrap {
+            " \\"
+        } else {
+            ""
+        }).trim_end());
+        state.line_buffer.clear();
+        state.need_nl = true;
+    }
+
+    fn flush(&self, state: &mut State, out: &mut String, wrapping: bool) {
+        if !state.line_buffer.trim().is_empty() {
+            self.flush_line(state, out, wrapping);
+        }
     }
 
     fn write_breakable(&self, state: &mut State, out: &mut String, text: &str) {
-        let mut s = self.0.as_ref().borrow_mut();
-        let max_width = s.calc_max_width();
+        let max_width = state.line_states.get(self.0).unwrap().calc_max_width();
         let mut text = text;
         if state.line_buffer.is_empty() {
             text = text.trim_start();
@@ -427,12 +610,12 @@ impl LineState {
                         // Doesn't fit, but can split to get within line
                         state.line_buffer.push_str(text[..b].trim_end());
                         text = text[b..].trim_start();
-                        s.flush(state, out, !text.is_empty());
+                        self.flush(state, out, !text.is_empty());
                     },
                     None => {
                         if !state.line_buffer.is_empty() {
                             // Doesn't fit, can't split, but stuff in buffer - flush that first
-                            s.flush(state, out, true);
+                            self.flush(state, out, true);
                         } else {
                             // Doesn't fit, can't split, but buffer empty - just write it
                             state.line_buffer.push_str(text.trim_end());
@@ -449,45 +632,109 @@ impl LineState {
     }
 
     fn flush_always(&self, state: &mut State, out: &mut String) {
-        self.0.as_ref().borrow_mut().flush_always(state, out, false);
+        self.flush_line(state, out, false);
     }
 
     fn write_unbreakable(&self, state: &mut State, out: &mut String, text: &str) {
-        let mut s = self.0.as_ref().borrow_mut();
-        let max_width = s.calc_max_width();
+        let max_width = state.line_states.get(self.0).unwrap().calc_max_width();
         if state.line_buffer.chars().count() + text.chars().count() > max_width {
-            s.flush(state, out, true);
+            self.flush(state, out, true);
         }
         state.line_buffer.push_str(text);
     }
 
+    /// Like `write_unbreakable`, for GFM elements written directly against the word before
+    /// them (`word[^1]`, `(~~old~~)`). If it doesn't fit, that word moves to the new line too
+    /// rather than being split off, which would add a space.
+    fn write_attached(&self, state: &mut State, out: &mut String, text: &str) {
+        let max_width = state.line_states.get(self.0).unwrap().calc_max_width();
+        if state.line_buffer.chars().count() + text.chars().count() > max_width {
+            self.wrap(state, out);
+        }
+        state.line_buffer.push_str(text);
+    }
+
+    /// Starts a new line, taking along the word the buffer ends partway through since there's
+    /// no space to break at. Does nothing if the buffer is a single word.
+    fn wrap(&self, state: &mut State, out: &mut String) {
+        if let Some(i) = state.line_buffer.rfind(' ') {
+            let word = state.line_buffer.split_off(i + 1);
+            self.flush(state, out, true);
+            state.line_buffer = word;
+        }
+    }
+
     fn write_newline(&self, state: &mut State, out: &mut String) {
-        let mut s = self.0.as_ref().borrow_mut();
         if !state.line_buffer.is_empty() {
             panic!();
         }
-        s.flush_always(state, out, false);
+        self.flush_line(state, out, false);
     }
 }
 
-fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &Node, inline: bool) {
-    fn join_lines(text: &str) -> String {
-        let lines = text.lines().collect::<Vec<&str>>();
-        let mut joined = String::new();
-        for (i, line) in lines.iter().enumerate() {
-            let mut line = *line;
-            if i > 0 {
-                line = line.trim_start();
-                joined.push(' ');
-            }
-            if i < lines.len() - 1 {
-                line = line.trim_end();
-            }
-            joined.push_str(line);
+fn join_lines(text: &str) -> String {
+    let lines = text.lines().collect::<Vec<&str>>();
+    let mut joined = String::new();
+    for (i, line) in lines.iter().enumerate() {
+        let mut line = *line;
+        if i > 0 {
+            line = line.trim_start();
+            joined.push(' ');
+        }
+        if i < lines.len() - 1 {
+            line = line.trim_end();
         }
-        joined
+        joined.push_str(line);
     }
+    if text.ends_with('\n') {
+        // A line break before the next inline element
+        joined.push(' ');
+    }
+    joined
+}
+
+/// Writes inline elements. Text and literal autolinks next to each other are written
+/// together so lines are only broken at spaces and not between a url and the text
+/// around it.
+fn write_inlines(state: &mut State, out: &mut String, line: LineState, children: &[Node]) {
+    fn write_text(state: &mut State, out: &mut String, line: LineState, text: &str, attached: bool) {
+        let mut text = join_lines(text);
+        if attached && !text.starts_with(' ') {
+            // Punctuation etc. right after a GFM element stays with it
+            let rest = text.split_off(text.find(' ').unwrap_or(text.len()));
+            line.write_attached(state, out, &text);
+            text = rest;
+        }
+        line.write_breakable(state, out, &text);
+    }
+
+    let mut text = String::new();
 
+    // Whether the last element was a GFM element that the following text is attached to
+    let mut attached = false;
+    for child in children {
+        match child {
+            Node::Text(x) => text.push_str(&x.value),
+            Node::Link(x) if is_literal_autolink(state.source, x) => {
+                let p = x.position.as_ref().unwrap();
+                text.push_str(&state.source[p.start.offset .. p.end.offset]);
+            },
+            _ => {
+                if !text.is_empty() {
+                    write_text(state, out, line, &text, attached);
+                    text.clear();
+                }
+                recurse_write(state, out, line.clone_inline(), child, true);
+                attached = matches!(child, Node::Delete(_) | Node::FootnoteReference(_));
+            },
+        }
+    }
+    if !text.is_empty() {
+        write_text(state, out, line, &text, attached);
+    }
+}
+
+fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &Node, inline: bool) {
     match node {
         // block->block elements (newline between)
         Node::Root(x) => {
@@ -495,11 +742,12 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
                 if i > 0 {
                     line.write_newline(state, out);
                 }
-                recurse_write(state, out, line.clone_zero_indent(), child, false);
+                let child_line = line.clone_zero_indent(state);
+                recurse_write(state, out, child_line, child, false);
             }
         },
         Node::BlockQuote(x) => {
-            let line = line.clone_indent(None, "> ".into(), false);
+            let line = line.clone_indent(state, None, "> ".into(), false);
             for (i, child) in x.children.iter().enumerate() {
                 if i > 0 {
                     line.write_newline(state, out);
@@ -508,6 +756,11 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
             }
         },
         Node::List(x) => {
+            let task = |child: &Node| match child {
+                Node::ListItem(ListItem { checked: Some(true), .. }) => "[x] ",
+                Node::ListItem(ListItem { checked: Some(false), .. }) => "[ ] ",
+                _ => "",
+            };
             match &x.start {
                 Some(i) => {
                     // bug in markdown lib, start is actually the number of the last child:
@@ -516,13 +769,14 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
                         if j > 0 {
                             line.write_newline(state, out);
                         }
-                        recurse_write(
-                            state,
-                            out,
-                            line.clone_indent(Some(format!("{}. ", *i as usize + j)), "   ".into(), false),
-                            child,
-                            false,
-                        );
+                        let child_line =
+                            line.clone_indent(
+                                state,
+                                Some(format!("{}. {}", *i as usize + j, task(child))),
+                                "   ".into(),
+                                false,
+                            );
+                        recurse_write(state, out, child_line, child, false);
                     }
                 },
                 None => {
@@ -530,13 +784,9 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
                         if i > 0 {
                             line.write_newline(state, out);
                         }
-                        recurse_write(
-                            state,
-                            out,
-                            line.clone_indent(Some("* ".into()), "   ".into(), false),
-                            child,
-                            false,
-                        );
+                        let child_line =
+                            line.clone_indent(state, Some(format!("* {}", task(child))), "   ".into(), false);
+                        recurse_write(state, out, child_line, child, false);
                     }
                 },
             };
@@ -546,17 +796,31 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
                 if i > 0 {
                     line.write_newline(state, out);
                 }
-                recurse_write(state, out, line.clone_zero_indent(), child, false);
+                let child_line = line.clone_zero_indent(state);
+                recurse_write(state, out, child_line, child, false);
             }
         },
         // block->inline elements (flush after)
         Node::Code(x) => {
-            line.write_unbreakable(state, out, &format!("```{}", match &x.lang {
-                None => "",
-                Some(x) => x,
-            }));
+            let info = match (&x.lang, &x.meta) {
+                (None, _) => "".to_string(),
+                (Some(lang), None) => lang.clone(),
+                (Some(lang), Some(meta)) => format!("{} {}", lang, meta),
+            };
+            let formatted = match state.code_config {
+                Some(config) if is_rust_block(&info) => {
+                    // Make room for the comment prefix
+                    let prefix_width = state.line_states.get(line.0).unwrap().prefix.chars().count();
+                    format_doc_example(&x.value, &FormatConfig {
+                        max_width: config.max_width.saturating_sub(prefix_width),
+                        ..*config
+                    })
+                },
+                _ => None,
+            };
+            line.write_unbreakable(state, out, &format!("```{}", info));
             line.flush_always(state, out);
-            for l in x.value.as_str().lines() {
+            for l in formatted.as_deref().unwrap_or(&x.value).lines() {
                 line.write_unbreakable(state, out, l);
                 line.flush_always(state, out);
             }
@@ -564,18 +828,20 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
             line.flush_always(state, out);
         },
         Node::Heading(x) => {
-            let line = line.clone_indent(Some(format!("{} ", "#".repeat(x.depth as usize))), "  ".into(), true);
-            for child in &x.children {
-                recurse_write(state, out, line.clone_inline(), child, true);
-            }
+            let line =
+                line.clone_indent(state, Some(format!("{} ", "#".repeat(x.depth as usize))), "  ".into(), true);
+            write_inlines(state, out, line, &x.children);
             line.flush_always(state, out);
         },
         Node::FootnoteDefinition(x) => {
-            let line = line.clone_indent(Some(format!("[^{}]: ", x.identifier)), "   ".into(), false);
-            for child in &x.children {
-                recurse_write(state, out, line.clone_inline(), child, true);
+            let line = line.clone_indent(state, Some(format!("[^{}]: ", x.identifier)), "    ".into(), false);
+            for (i, child) in x.children.iter().enumerate() {
+                if i > 0 {
+                    line.write_newline(state, out);
+                }
+                let child_line = line.clone_zero_indent(state);
+                recurse_write(state, out, child_line, child, false);
             }
-            line.flush_always(state, out);
         },
         Node::ThematicBreak(_) => {
             line.write_unbreakable(state, out, "---");
@@ -591,9 +857,7 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
             line.flush_always(state, out);
         },
         Node::Paragraph(x) => {
-            for child in &x.children {
-                recurse_write(state, out, line.clone_inline(), child, true);
-            }
+            write_inlines(state, out, line, &x.children);
             line.flush_always(state, out);
         },
         Node::Html(x) if !inline => {
@@ -609,27 +873,26 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
         },
         Node::Strong(x) => {
             line.write_unbreakable(state, out, "**");
-            for child in &x.children {
-                recurse_write(state, out, line.clone_inline(), child, true);
-            }
+            write_inlines(state, out, line, &x.children);
             line.write_unbreakable(state, out, "**");
         },
         Node::Delete(x) => {
-            line.write_unbreakable(state, out, "~~");
-            for child in &x.children {
-                recurse_write(state, out, line.clone_inline(), child, true);
-            }
-            line.write_unbreakable(state, out, "~~");
+            // Keep single or double tildes as written
+            let marker = match x.position.as_ref().and_then(|p| state.source.get(p.start.offset .. p.end.offset)) {
+                Some(t) if !t.starts_with("~~") => "~",
+                _ => "~~",
+            };
+            line.write_attached(state, out, marker);
+            write_inlines(state, out, line, &x.children);
+            line.write_attached(state, out, marker);
         },
         Node::Emphasis(x) => {
             line.write_unbreakable(state, out, "_");
-            for child in &x.children {
-                recurse_write(state, out, line.clone_inline(), child, true);
-            }
+            write_inlines(state, out, line, &x.children);
             line.write_unbreakable(state, out, "_");
         },
         Node::FootnoteReference(x) => {
-            line.write_unbreakable(state, out, &format!("[^{}]", x.identifier));
+            line.write_attached(state, out, &format!("[^{}]", x.identifier));
         },
         Node::Html(x) => {
             line.write_unbreakable(state, out, &format!("`{}`", join_lines(&x.value)));
@@ -663,6 +926,11 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
         Node::ImageReference(x) => {
             line.write_unbreakable(state, out, &format!("![][{}]", x.identifier));
         },
+        Node::Link(x) if is_literal_autolink(state.source, x) => {
+            // A bare url, `www.` link or email address - keep it as written
+            let p = x.position.as_ref().unwrap();
+            line.write_attached(state, out, &state.source[p.start.offset .. p.end.offset]);
+        },
         Node::Link(x) => {
             let simple_text = if x.children.len() != 1 {
                 None
@@ -697,16 +965,12 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
                 },
                 (None, None) => {
                     line.write_unbreakable(state, out, "[");
-                    for child in &x.children {
-                        recurse_write(state, out, line.clone_inline(), child, true);
-                    }
+                    write_inlines(state, out, line, &x.children);
                     line.write_unbreakable(state, out, &format!("]({})", x.url));
                 },
                 (None, Some(title)) => {
                     line.write_unbreakable(state, out, "[");
-                    for child in &x.children {
-                        recurse_write(state, out, line.clone_inline(), child, true);
-                    }
+                    write_inlines(state, out, line, &x.children);
                     line.write_unbreakable(state, out, &format!("]({}", x.url));
                     line.write_unbreakable(state, out, " \"");
                     line.write_breakable(state, out, title);
@@ -740,9 +1004,7 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
                 },
                 _ => {
                     line.write_unbreakable(state, out, "[");
-                    for child in &x.children {
-                        recurse_write(state, out, line.clone_inline(), child, true);
-                    }
+                    write_inlines(state, out, line, &x.children);
                     line.write_unbreakable(state, out, &format!("][{}]", x.identifier));
                 },
             }
@@ -750,10 +1012,14 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
         Node::Break(_) => {
             // normalized out
         },
+        Node::Table(x) => {
+            write_table(state, out, line, x);
+        },
+        Node::TableRow(_) | Node::TableCell(_) => {
+            // Written along with the table
+            unreachable!();
+        },
         Node::Math(_) => unreachable!(),
-        Node::Table(_) => unreachable!(),
-        Node::TableRow(_) => unreachable!(),
-        Node::TableCell(_) => unreachable!(),
         Node::MdxJsxTextElement(_) => unreachable!(),
         Node::MdxFlowExpression(_) => unreachable!(),
         Node::MdxJsxFlowElement(_) => unreachable!(),
@@ -765,12 +1031,141 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
     }
 }
 
+/// Whether a link is a GFM literal autolink (plain text in the source, not in `<>` or
+/// `[]`).
+fn is_literal_autolink(source: &str, link: &Link) -> bool {
+    match link.position.as_ref().and_then(|p| source.get(p.start.offset .. p.end.offset)) {
+        Some(text) => !text.starts_with('<') && !text.starts_with('[') && !text.contains(char::is_whitespace),
+        None => false,
+    }
+}
+
+/// Writes a table with columns padded to line up, or as it was in the source if that
+/// would be too wide.
+fn write_table(state: &mut State, out: &mut String, line: LineState, table: &markdown::mdast::Table) {
+    // Render cells on their own first to get the column widths
+    let mut rows = vec![];
+    for row in &table.children {
+        let mut cells = vec![];
+        if let Node::TableRow(row) = row {
+            for cell in &row.children {
+                let mut cell_state = State {
+                    source: state.source,
+                    code_config: None,
+                    line_buffer: String::new(),
+                    need_nl: false,
+                    line_states: vec![],
+                };
+                let mut cell_out = String::new();
+                let cell_line = LineState::new(&mut cell_state, None, "".into(), usize::MAX, None, false);
+                if let Node::TableCell(cell) = cell {
+                    write_inlines(&mut cell_state, &mut cell_out, cell_line, &cell.children);
+                }
+                cell_line.flush(&mut cell_state, &mut cell_out, false);
+                cells.push(cell_out.trim().replace('|', "\\|"));
+            }
+        }
+        rows.push(cells);
+    }
+    let mut widths = vec![
+        3usize;
+        table.align.len()
+    ];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.width());
+        }
+    }
+    let columns = widths.len();
+    let table_width = widths.iter().sum::<usize>() + columns * 3 + 1;
+    let line_state = state.line_states.get(line.0).unwrap();
+    if line_state.prefix.width() + table_width > line_state.calc_max_width() {
+        if let Some(position) = &table.position {
+            if let Some(text) = state.source.get(position.start.offset .. position.end.offset) {
+                for (i, l) in text.lines().enumerate() {
+                    let mut l = l;
+                    if i > 0 {
+                        // Drop the indentation or quote markers of any containing blocks
+                        for _ in 1 .. position.start.column {
+                            match l.strip_prefix(|c: char| c == ' ' || c == '>') {
+                                Some(rest) => l = rest,
+                                None => break,
+                            }
+                        }
+                    }
+                    line.write_unbreakable(state, out, l.trim());
+                    line.flush_always(state, out);
+                }
+                return;
+            }
+        }
+    }
+    let align = |i: usize| table.align.get(i).copied().unwrap_or(AlignKind::None);
+    let write_row = |state: &mut State, out: &mut String, cells: Vec<String>| {
+        line.write_unbreakable(state, out, &format!("| {} |", cells.join(" | ")));
+        line.flush_always(state, out);
+    };
+    for (row_i, row) in rows.iter().enumerate() {
+        let mut cells = vec![];
+        for (i, width) in widths.iter().enumerate() {
+            let cell = row.get(i).map(|c| c.as_str()).unwrap_or("");
+            let pad = width - cell.width();
+            let (left, right) = match align(i) {
+                AlignKind::Right => (pad, 0),
+                AlignKind::Center => (pad / 2, pad - pad / 2),
+                AlignKind::Left | AlignKind::None => (0, pad),
+            };
+            cells.push(format!("{}{}{}", " ".repeat(left), cell, " ".repeat(right)));
+        }
+
+        // Cells past the header's aren't part of the table but keep them anyway
+        cells.extend(row.iter().skip(columns).cloned());
+        write_row(state, out, cells);
+        if row_i == 0 {
+            write_row(state, out, widths.iter().enumerate().map(|(i, width)| match align(i) {
+                AlignKind::Left => format!(":{}", "-".repeat(width - 1)),
+                AlignKind::Right => format!("{}:", "-".repeat(width - 1)),
+                AlignKind::Center => format!(":{}:", "-".repeat(width - 2)),
+                AlignKind::None => "-".repeat(*width),
+            }).collect());
+        }
+    }
+}
+
+/// Rustdoc's markdown dialect, CommonMark plus GFM extensions.
+pub(crate) fn md_parse_options() -> markdown::ParseOptions {
+    markdown::ParseOptions {
+        constructs: markdown::Constructs {
+            gfm_autolink_literal: true,
+            gfm_footnote_definition: true,
+            gfm_label_start_footnote: true,
+            gfm_strikethrough: true,
+            gfm_table: true,
+            gfm_task_list_item: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
 pub fn format_md(
     true_out: &mut String,
     max_width: usize,
     rel_max_width: Option<usize>,
     prefix: &str,
     source: &str,
+) -> Result<()> {
+    format_md_with_code(true_out, max_width, rel_max_width, prefix, source, None)
+}
+
+/// Like `format_md` but also formats Rust code blocks using `code_config`, if set.
+pub(crate) fn format_md_with_code(
+    true_out: &mut String,
+    max_width: usize,
+    rel_max_width: Option<usize>,
+    prefix: &str,
+    source: &str,
+    code_config: Option<&FormatConfig>,
 ) -> Result<()> {
     // TODO, due to a bug a bunch of unreachable branches might have had code added.  I'd
     // like to go back and see if some block-level starts can be removed in contexts they
@@ -778,20 +1173,15 @@ pub fn format_md(
     match es!({
         let mut out = String::new();
         let mut state = State {
+            source,
+            code_config,
             line_buffer: String::new(),
             need_nl: false,
+            line_states: vec![],
         };
-        let ast = markdown::to_mdast(source, &markdown::ParseOptions {
-            constructs: markdown::Constructs { ..Default::default() },
-            ..Default::default()
-        }).map_err(|e| anyhow!("{}", e))?;
-        recurse_write(
-            &mut state,
-            &mut out,
-            LineState::new(None, prefix.to_string(), max_width, rel_max_width, false),
-            &ast,
-            false,
-        );
+        let ast = markdown::to_mdast(source, &md_parse_options()).map_err(|e| anyhow!("{}", e))?;
+        let line = LineState::new(&mut state, None, prefix.to_string(), max_width, rel_max_width, false);
+        recurse_write(&mut state, &mut out, line, &ast, false);
         Ok(out)
     }) {
         Ok(o) => {
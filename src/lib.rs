@@ -13,18 +13,27 @@ pub use comments::{
     format_md,
     HashLineColumn,
 };
+use comments::{
+    extract_comments_with,
+    format_md_with_code,
+};
+pub use snippets::format_md_code_blocks;
 use proc_macro2::{
     Ident,
     LineColumn,
 };
 use quote::ToTokens;
-use sg_general::append_comments;
-use std::{
-    cell::RefCell,
-    collections::HashMap,
-    rc::Rc,
+use sg_general::{
+    append_comments,
+    append_statement,
+    append_statement_list_raw,
+    want_statement_margin,
+};
+use std::collections::HashMap;
+use syn::{
+    File,
+    Item,
 };
-use syn::File;
 
 pub(crate) mod comments;
 pub(crate) mod sg_expr;
@@ -34,6 +43,7 @@ pub(crate) mod sg_statement;
 pub(crate) mod sg_type;
 pub(crate) mod sg_root;
 pub(crate) mod sg_general_lists;
+pub(crate) mod snippets;
 pub mod utils;
 
 pub(crate) trait TrivialLineColMath {
@@ -62,7 +72,14 @@ pub enum CommentMode {
     Verbatim,
 }
 
-#[derive(Debug)]
+/// Whether a comment is written with `//` or `/* */`.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum CommentStyle {
+    Line,
+    Block,
+}
+
+#[derive(Debug, Clone)]
 pub struct Comment {
     // Special loc (0, 1) == end of file
     pub loc: LineColumn,
@@ -70,13 +87,45 @@ pub struct Comment {
     pub lines: String,
 }
 
+/// A comment along with how it was written relative to the code around it, which
+/// affects where and how it's formatted.
+#[derive(Debug, Clone)]
+pub(crate) struct ExtractedComment {
+    pub(crate) comment: Comment,
+    /// The style it was written in
+    pub(crate) style: CommentStyle,
+    /// Follows code at the end of a line and should stay there if possible
+    pub(crate) trailing: bool,
+    /// A `/* */` comment on the same line as the code after it, which stays there
+    pub(crate) inline: bool,
+}
+
+impl ExtractedComment {
+    /// Whether the comment goes on its own lines before the code it's attached to
+    pub(crate) fn is_leading(&self) -> bool {
+        !self.trailing && !self.inline
+    }
+}
+
+impl From<Comment> for ExtractedComment {
+    // Without anything else known about it, written as a line comment on its own lines
+    fn from(comment: Comment) -> Self {
+        ExtractedComment {
+            comment,
+            style: CommentStyle::Line,
+            trailing: false,
+            inline: false,
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct SplitGroupIdx(usize);
 
 #[derive(Clone, Copy)]
 pub struct SegmentIdx(usize);
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub(crate) struct LineIdx(usize);
 
 pub struct SplitGroup {
@@ -100,7 +149,11 @@ pub(crate) struct SegmentLine {
 #[derive(Debug)]
 pub(crate) enum SegmentContent {
     Text(String),
-    Comment((Alignment, Vec<Comment>)),
+    // The location of the comments in `placed_comments`, only those that aren't inline are
+    // rendered
+    Comment((Alignment, HashLineColumn)),
+    // Block comments rendered between tokens, without breaking the line
+    InlineComment(String),
     Break(Alignment, bool),
 }
 
@@ -112,49 +165,143 @@ pub(crate) struct Segment {
 }
 
 pub(crate) struct Line {
-    index: usize,
+    prev: Option<LineIdx>,
+    next: Option<LineIdx>,
     segs: Vec<SegmentIdx>,
+    // Total width of text segments, kept up to date as segments move between lines
+    text_width: usize,
+    // Break segments on the line; their width depends on split/alignment state so it's
+    // computed on demand
+    breaks: Vec<SegmentIdx>,
 }
 
 struct Lines {
     owned_lines: Vec<Line>,
-    lines: Vec<LineIdx>,
+    first: Option<LineIdx>,
+    debug_checks: bool,
 }
 
 pub struct MakeSegsState {
     nodes: Vec<SplitGroup>,
     segs: Vec<Segment>,
-    comments: HashMap<HashLineColumn, Vec<Comment>>,
+    alignments: Vec<Alignment_>,
+    comments: HashMap<HashLineColumn, Vec<ExtractedComment>>,
+    // Comments taken from `comments` by `append_comments`, referred to by their location from
+    // comment segments
+    placed_comments: HashMap<HashLineColumn, Vec<ExtractedComment>>,
+    // Every location comments could be placed at, in the order they're built
+    comment_anchors: Vec<LineColumn>,
+    // The locations of all the comments to place, in source order
+    comment_locs: Vec<LineColumn>,
+    moved_comments: Vec<MovedComments>,
     split_brace_threshold: Option<usize>,
     split_attributes: bool,
     split_where: bool,
 }
 
+fn loc_key(loc: &LineColumn) -> (usize, usize) {
+    (loc.line, loc.column)
+}
+
+impl MakeSegsState {
+    /// Returns the comments from `start` up to the last of `anchors` that are still waiting
+    /// to be placed, because the tokens they're before don't support comments.
+    pub(crate) fn unplaced_comments(&self, start: LineColumn, anchors: &[LineColumn]) -> Vec<LineColumn> {
+        let end = match anchors.iter().map(loc_key).max() {
+            Some(e) => e,
+            None => return vec![],
+        };
+        let first = self.comment_locs.partition_point(|l| loc_key(l) < loc_key(&start));
+        self.comment_locs[first..]
+            .iter()
+            .take_while(|l| loc_key(l) <= end)
+            .filter(|l| self.comments.contains_key(&HashLineColumn(**l)))
+            .copied()
+            .collect()
+    }
+
+    /// Moves the unplaced comments at `from` to before the token at `to`, or the end of the
+    /// file if `None`, and records the move to warn about.
+    pub(crate) fn move_comments(&mut self, from: LineColumn, to: Option<LineColumn>) {
+        let mut moved = self.comments.remove(&HashLineColumn(from)).unwrap();
+        for comment in &mut moved {
+            comment.trailing = false;
+        }
+        let target = to.unwrap_or(LineColumn {
+            line: 0,
+            column: 1,
+        });
+        let target_comments = self.comments.entry(HashLineColumn(target)).or_default();
+        if to.is_some() && loc_key(&target) > loc_key(&from) {
+            moved.append(target_comments);
+            *target_comments = moved;
+        } else {
+            target_comments.append(&mut moved);
+        }
+        self.moved_comments.push(MovedComments {
+            from,
+            to,
+        });
+    }
+}
+
 pub(crate) fn check_split_brace_threshold(out: &MakeSegsState, count: usize) -> bool {
     out.split_brace_threshold.map(|t| count >= t).unwrap_or(false)
 }
 
+fn seg_visible(out: &MakeSegsState, seg_i: SegmentIdx) -> bool {
+    let seg = out.segs.get(seg_i.0).unwrap();
+    let node = out.nodes.get(seg.node.0).unwrap();
+    match (&seg.mode, node.split) {
+        (SegmentMode::All, _) => true,
+        (SegmentMode::Unsplit, true) => false,
+        (SegmentMode::Unsplit, false) => true,
+        (SegmentMode::Split, true) => true,
+        (SegmentMode::Split, false) => false,
+    }
+}
+
+/// Whether the first text on `line` is a closing bracket.
+fn line_starts_with_close(out: &MakeSegsState, line: &Line) -> bool {
+    line.segs.iter().copied().filter(|seg_i| seg_visible(out, *seg_i)).find_map(|seg_i| {
+        match &out.segs.get(seg_i.0).unwrap().content {
+            SegmentContent::Break(..) => None,
+            SegmentContent::Text(t) => Some(t.trim_start().starts_with([')', ']', '}'])),
+            _ => Some(false),
+        }
+    }).unwrap_or(false)
+}
+
+fn seg_text_width(seg: &Segment) -> usize {
+    match &seg.content {
+        SegmentContent::Text(t) => t.chars().count(),
+        // Plus spaces on either side, which may or may not be needed
+        SegmentContent::InlineComment(t) => t.chars().count() + 2,
+        _ => 0,
+    }
+}
+
 pub(crate) fn line_length(out: &MakeSegsState, lines: &Lines, line_i: LineIdx) -> usize {
-    let mut len = 0;
-    for seg_i in &lines.owned_lines.get(line_i.0).unwrap().segs {
+    let line = lines.owned_lines.get(line_i.0).unwrap();
+    let mut len = line.text_width;
+    for seg_i in &line.breaks {
         let seg = out.segs.get(seg_i.0).unwrap();
-        match &seg.content {
-            SegmentContent::Text(t) => len += t.chars().count(),
-            SegmentContent::Break(b, _) => {
-                if out.nodes.get(seg.node.0).unwrap().split {
-                    len += b.get();
-                }
-            },
-            SegmentContent::Comment(_) => { },
-        };
+        if let SegmentContent::Break(b, _) = &seg.content {
+            if out.nodes.get(seg.node.0).unwrap().split {
+                len += b.get(out);
+            }
+        }
     }
     len
 }
 
 pub(crate) fn split_group(out: &mut MakeSegsState, lines: &mut Lines, sg_i: SplitGroupIdx) {
-    let mut sg = out.nodes.get_mut(sg_i.0).unwrap();
+    let sg = out.nodes.get_mut(sg_i.0).unwrap();
     sg.split = true;
-    for seg_i in &sg.segments.clone() {
+
+    // Split from the end so each split only moves the segments up to the next break rather
+    // than the whole rest of the line
+    for seg_i in sg.segments.clone().iter().rev() {
         let res = {
             let seg = out.segs.get(seg_i.0).unwrap();
             match (&seg.mode, &seg.content) {
@@ -171,6 +318,21 @@ pub(crate) fn split_group(out: &mut MakeSegsState, lines: &mut Lines, sg_i: Spli
     }
 }
 
+pub(crate) fn activate_line_start(out: &mut MakeSegsState, seg_i: SegmentIdx) {
+    let seg = out.segs.get(seg_i.0).unwrap();
+    let a = match &seg.content {
+        SegmentContent::Break(a, activate) => {
+            if !*activate {
+                return;
+            }
+            *a
+        },
+        SegmentContent::Comment((a, _)) => *a,
+        _ => return,
+    };
+    a.activate(out);
+}
+
 pub(crate) fn split_line_at(
     out: &mut MakeSegsState,
     lines: &mut Lines,
@@ -179,67 +341,103 @@ pub(crate) fn split_line_at(
     inject_start: Option<SegmentIdx>,
 ) {
     let line = lines.owned_lines.get_mut(line_idx.0).unwrap();
+    let moved = line.segs.split_off(off);
+    for seg_i in &moved {
+        line.text_width -= seg_text_width(out.segs.get(seg_i.0).unwrap());
+    }
     let mut new_segs = vec![];
     if let Some(s) = inject_start {
         new_segs.push(s);
     }
-    new_segs.extend(line.segs.split_off(off));
-    {
-        let seg_i = new_segs.get(0).unwrap();
-        let seg = out.segs.get(seg_i.0).unwrap();
-        match &seg.content {
-            SegmentContent::Break(a, activate) => {
-                if *activate {
-                    a.activate();
-                }
-            },
-            SegmentContent::Comment((a, _)) => {
-                a.activate();
-            },
-            _ => { },
-        };
-    }
-    let insert_at = line.index + 1;
-    insert_line(out, lines, insert_at, new_segs);
+    new_segs.extend(moved);
+    activate_line_start(out, *new_segs.first().unwrap());
+    insert_line(out, lines, line_idx, new_segs);
 }
 
-pub(crate) fn insert_line(out: &mut MakeSegsState, lines: &mut Lines, at: usize, segs: Vec<SegmentIdx>) {
+fn push_line(
+    out: &mut MakeSegsState,
+    lines: &mut Lines,
+    prev: Option<LineIdx>,
+    next: Option<LineIdx>,
+    segs: Vec<SegmentIdx>,
+) -> LineIdx {
     let line_i = LineIdx(lines.owned_lines.len());
+    let mut text_width = 0;
+    let mut breaks = vec![];
+    for (i, seg_i) in segs.iter().enumerate() {
+        let seg = out.segs.get_mut(seg_i.0).unwrap();
+        text_width += seg_text_width(seg);
+        if let SegmentContent::Break(..) = &seg.content {
+            breaks.push(*seg_i);
+        }
+        seg.line = Some(SegmentLine {
+            line: line_i,
+            seg_index: i,
+        });
+    }
     lines.owned_lines.push(Line {
-        index: at,
+        prev,
+        next,
         segs,
+        text_width,
+        breaks,
     });
-    for (i, seg_i) in lines.owned_lines.get(line_i.0).unwrap().segs.iter().enumerate() {
-        let mut seg = out.segs.get_mut(seg_i.0).unwrap();
-        match seg.line.as_mut() {
-            Some(l) => {
-                l.line = line_i;
-                l.seg_index = i;
-            },
-            None => {
-                seg.line = Some(SegmentLine {
-                    line: line_i,
-                    seg_index: i,
-                });
-            },
-        };
+    match prev {
+        Some(prev) => lines.owned_lines.get_mut(prev.0).unwrap().next = Some(line_i),
+        None => lines.first = Some(line_i),
     }
-    lines.lines.insert(at, line_i);
-    for (i, line_i) in lines.lines.iter().enumerate().skip(at + 1) {
-        lines.owned_lines.get_mut(line_i.0).unwrap().index = i;
+    if let Some(next) = next {
+        lines.owned_lines.get_mut(next.0).unwrap().prev = Some(line_i);
     }
-    for (i, line_i) in lines.lines.iter().enumerate() {
+    line_i
+}
+
+pub(crate) fn insert_line(out: &mut MakeSegsState, lines: &mut Lines, after: LineIdx, segs: Vec<SegmentIdx>) {
+    let next = lines.owned_lines.get(after.0).unwrap().next;
+    push_line(out, lines, Some(after), next, segs);
+    {
+        // Moved segments are always a suffix of the previous line, so are its moved breaks
+        let after_breaks = &mut lines.owned_lines.get_mut(after.0).unwrap().breaks;
+        while let Some(seg_i) = after_breaks.last() {
+            if out.segs.get(seg_i.0).unwrap().line.as_ref().unwrap().line == after {
+                break;
+            }
+            after_breaks.pop();
+        }
+    }
+    if lines.debug_checks {
+        check_lines(out, lines, &format!("after insert after line {}", after.0));
+    }
+}
+
+fn check_lines(out: &MakeSegsState, lines: &Lines, context: &str) {
+    let mut prev = None;
+    let mut at = lines.first;
+    while let Some(line_i) = at {
         let line = lines.owned_lines.get(line_i.0).unwrap();
-        assert_eq!(line.index, i, "line index wrong; after insert at line {}", at);
+        assert_eq!(line.prev, prev, "line {} prev link wrong; {}", line_i.0, context);
+        let mut text_width = 0;
+        let mut breaks = vec![];
         for (j, seg_i) in line.segs.iter().enumerate() {
-            assert_eq!(
-                out.segs.get(seg_i.0).unwrap().line.as_ref().unwrap().seg_index,
-                j,
-                "seg index wrong; on line {}, after insert at line {}",
-                i,
-                at
-            );
+            let seg = out.segs.get(seg_i.0).unwrap();
+            let seg_line = seg.line.as_ref().unwrap();
+            assert_eq!(seg_line.line, line_i, "seg line wrong; on line {}, {}", line_i.0, context);
+            assert_eq!(seg_line.seg_index, j, "seg index wrong; on line {}, {}", line_i.0, context);
+            text_width += seg_text_width(seg);
+            if let SegmentContent::Break(..) = &seg.content {
+                breaks.push(seg_i.0);
+            }
         }
+        assert_eq!(line.text_width, text_width, "text width wrong; on line {}, {}", line_i.0, context);
+        assert_eq!(
+            line.breaks.iter().map(|s| s.0).collect::<Vec<_>>(),
+            breaks,
+            "breaks wrong; on line {}, {}",
+            line_i.0,
+            context
+        );
+        prev = Some(line_i);
+        at = line.next;
     }
 }
 
@@ -248,8 +446,8 @@ pub(crate) struct Alignment_ {
     pub(crate) active: bool,
 }
 
-#[derive(Clone)]
-pub struct Alignment(Rc<RefCell<Alignment_>>);
+#[derive(Clone, Copy)]
+pub struct Alignment(usize);
 
 impl std::fmt::Debug for Alignment {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -258,26 +456,29 @@ impl std::fmt::Debug for Alignment {
 }
 
 impl Alignment {
-    pub(crate) fn indent(&self) -> Alignment {
-        Alignment(Rc::new(RefCell::new(Alignment_ {
-            parent: Some(self.clone()),
+    pub(crate) fn indent(&self, out: &mut MakeSegsState) -> Alignment {
+        let idx = Alignment(out.alignments.len());
+        out.alignments.push(Alignment_ {
+            parent: Some(*self),
             active: false,
-        })))
+        });
+        idx
     }
 
-    pub(crate) fn activate(&self) -> usize {
-        self.0.borrow_mut().active = true;
-        self.get()
+    pub(crate) fn activate(&self, out: &mut MakeSegsState) -> usize {
+        out.alignments.get_mut(self.0).unwrap().active = true;
+        self.get(out)
     }
 
-    pub(crate) fn get(&self) -> usize {
-        let parent = match &self.0.as_ref().borrow().parent {
-            Some(p) => p.get(),
+    pub(crate) fn get(&self, out: &MakeSegsState) -> usize {
+        let a = out.alignments.get(self.0).unwrap();
+        let parent = match &a.parent {
+            Some(p) => p.get(out),
             None => {
                 return 0usize;
             },
         };
-        if self.0.as_ref().borrow_mut().active {
+        if a.active {
             4usize + parent
         } else {
             parent
@@ -477,6 +678,23 @@ pub struct FormatConfig {
     pub split_where: bool,
     pub comment_width: Option<usize>,
     pub comment_errors_fatal: bool,
+    /// Verify line bookkeeping after every change; very slow, for debugging the formatter
+    pub debug_checks: bool,
+    /// Format each top-level item of a file independently and join the results. The whole
+    /// file is still parsed into one syntax tree first, so this only bounds the formatter's
+    /// own state (segments and lines) by the largest item, not peak memory overall
+    pub item_by_item: bool,
+    /// Also format item by item when the source is larger than this many bytes
+    pub item_by_item_threshold: Option<usize>,
+    /// Leave `//` comments at the end of a line there when the line fits, instead of moving
+    /// them above the line
+    pub keep_trailing_comments: bool,
+    /// Write comments on their own lines in this style, or `None` to keep the style of each
+    /// comment
+    pub comment_style: Option<CommentStyle>,
+    /// Treat non-doc comments that parse as Rust code as verbatim (`//.`) comments rather
+    /// than reflowing them as markdown
+    pub detect_commented_code: bool,
 }
 
 impl Default for FormatConfig {
@@ -490,6 +708,12 @@ impl Default for FormatConfig {
             comment_width: Some(80usize),
             comment_errors_fatal: false,
             quiet: false,
+            debug_checks: false,
+            item_by_item: false,
+            item_by_item_threshold: None,
+            keep_trailing_comments: false,
+            comment_style: Some(CommentStyle::Line),
+            detect_commented_code: false,
         }
     }
 }
@@ -497,13 +721,45 @@ impl Default for FormatConfig {
 pub struct FormatRes {
     pub rendered: String,
     pub lost_comments: HashMap<HashLineColumn, Vec<Comment>>,
+    pub moved_comments: Vec<MovedComments>,
+}
+
+/// Comments that were before a token that doesn't support comments, so they were
+/// moved somewhere else.
+#[derive(Debug)]
+pub struct MovedComments {
+    pub from: LineColumn,
+    /// The location of the token they were moved before, or `None` if they were moved to
+    /// the end of the file
+    pub to: Option<LineColumn>,
+}
+
+impl std::fmt::Display for MovedComments {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.to {
+            Some(to) => write!(
+                f,
+                "Comments before {}:{} can't be placed there, moved them to before {}:{}",
+                self.from.line,
+                self.from.column,
+                to.line,
+                to.column
+            ),
+            None => write!(
+                f,
+                "Comments before {}:{} can't be placed there, moved them to the end of the file",
+                self.from.line,
+                self.from.column
+            ),
+        }
+    }
 }
 
 pub use comments::extract_comments;
 
 pub fn format_str(source: &str, config: &FormatConfig) -> Result<FormatRes> {
-    let (comments, tokens) = extract_comments(source)?;
-    format_ast(
+    let (comments, tokens) = extract_comments_with(source, config)?;
+    let ast =
         syn::parse2::<File>(
             tokens,
         ).map_err(
@@ -513,31 +769,160 @@ pub fn format_str(source: &str, config: &FormatConfig) -> Result<FormatRes> {
                 e.span().start().column,
                 e
             ),
-        )?,
-        config,
-        comments,
-    )
+        )?;
+    if config.item_by_item || config.item_by_item_threshold.map(|t| source.len() > t).unwrap_or(false) {
+        format_file_items(ast, config, comments)
+    } else {
+        format_ast_extracted(ast, config, comments)
+    }
+}
+
+fn format_file_items(
+    ast: File,
+    config: &FormatConfig,
+    mut comments: HashMap<HashLineColumn, Vec<ExtractedComment>>,
+) -> Result<FormatRes> {
+    let eof_loc = LineColumn {
+        line: 0,
+        column: 1,
+    };
+    let eof_comments = comments.remove(&HashLineColumn(eof_loc));
+
+    // Give each item the comments from its first token up to the next item's first token,
+    // anything before the first item goes with the file header (shebang, inner attrs)
+    let starts =
+        ast
+            .items
+            .iter()
+            .map(|i| i.to_token_stream().into_iter().next().map(|t| t.span().start()).unwrap_or(LineColumn {
+                line: 0,
+                column: 0,
+            }))
+            .collect::<Vec<LineColumn>>();
+    let mut chunk_comments = (0 ..= ast.items.len()).map(|_| HashMap::new()).collect::<Vec<_>>();
+    for (loc, c) in comments {
+        let i = starts.partition_point(|s| (s.line, s.column) <= (loc.0.line, loc.0.column));
+        chunk_comments.get_mut(i).unwrap().insert(loc, c);
+    }
+    if let Some(c) = eof_comments {
+        chunk_comments.last_mut().unwrap().insert(HashLineColumn(eof_loc), c);
+    }
+    let mut chunk_comments = chunk_comments.into_iter();
+    let mut rendered = String::new();
+    let mut lost_comments = HashMap::new();
+    let mut moved_comments = vec![];
+
+    // Render the inner attributes, then items, inserting margins the same way as
+    // `append_statement_list_raw`
+    let mut previous_margin_group = None;
+    {
+        let comments = chunk_comments.next().unwrap();
+        if !ast.attrs.is_empty() || !comments.is_empty() {
+            previous_margin_group = Some(MarginGroup::Attr);
+            let res = format_ast_extracted(|out: &mut MakeSegsState, base_indent: &Alignment| {
+                let mut sg = new_sg(out);
+                sg.initial_split();
+                append_statement_list_raw(out, base_indent, &mut sg, Some(&ast.attrs), &Vec::<Item>::new());
+                sg.build(out)
+            }, config, comments)?;
+            rendered.push_str(&res.rendered);
+            lost_comments.extend(res.lost_comments);
+            moved_comments.extend(res.moved_comments);
+        }
+    }
+    for ((item, start), mut comments) in ast.items.into_iter().zip(starts).zip(chunk_comments) {
+        // A comment at the end of the previous item's last line is already rendered
+        if let Some(start_comments) = comments.get_mut(&HashLineColumn(start)) {
+            if append_trailing_comment(&mut rendered, config.max_width, start_comments.first().unwrap()) {
+                start_comments.remove(0);
+                if start_comments.is_empty() {
+                    comments.remove(&HashLineColumn(start));
+                }
+            }
+        }
+        if let Some(previous_margin_group) = &previous_margin_group {
+            if want_statement_margin(&comments, previous_margin_group, &item, Some(start)) {
+                rendered.push('\n');
+            }
+        }
+
+        // Surround with the same segments as items in a statement list get so lines are measured
+        // and rendered the same way
+        let res = format_ast_extracted(|out: &mut MakeSegsState, base_indent: &Alignment| {
+            let mut sg = new_sg(out);
+            sg.initial_split();
+            append_statement(out, base_indent, &mut sg, Some(start), &item, |out, sg| {
+                sg.split(out, *base_indent, true);
+            });
+            sg.seg_unsplit(out, " ");
+            sg.build(out)
+        }, config, comments)?;
+        rendered.push_str(&res.rendered);
+        lost_comments.extend(res.lost_comments);
+        moved_comments.extend(res.moved_comments);
+        previous_margin_group = Some(item.want_margin().0);
+    }
+    Ok(FormatRes {
+        rendered,
+        lost_comments,
+        moved_comments,
+    })
 }
 
 pub fn format_ast(
     ast: impl Formattable,
     config: &FormatConfig,
     comments: HashMap<HashLineColumn, Vec<Comment>>,
+) -> Result<FormatRes> {
+    let comments = comments.into_iter().map(|(k, v)| (k, v.into_iter().map(ExtractedComment::from).collect()));
+    format_ast_extracted(ast, config, comments.collect())
+}
+
+fn format_ast_extracted(
+    ast: impl Formattable,
+    config: &FormatConfig,
+    comments: HashMap<HashLineColumn, Vec<ExtractedComment>>,
 ) -> Result<FormatRes> {
     // Build text
-    let mut out = MakeSegsState {
-        nodes: vec![],
-        segs: vec![],
-        comments,
-        split_brace_threshold: config.split_brace_threshold,
-        split_attributes: config.split_attributes,
-        split_where: config.split_where,
+    let build = |comments: HashMap<HashLineColumn, Vec<ExtractedComment>>, moved_comments| {
+        let mut comment_locs = comments.keys().map(|k| k.0).collect::<Vec<LineColumn>>();
+        comment_locs.sort_by_key(loc_key);
+        let mut out = MakeSegsState {
+            nodes: vec![],
+            segs: vec![],
+            alignments: vec![],
+            comments,
+            placed_comments: HashMap::new(),
+            comment_anchors: vec![],
+            comment_locs,
+            moved_comments,
+            split_brace_threshold: config.split_brace_threshold,
+            split_attributes: config.split_attributes,
+            split_where: config.split_where,
+        };
+        let base_indent = Alignment(out.alignments.len());
+        out.alignments.push(Alignment_ {
+            parent: None,
+            active: false,
+        });
+        let root = ast.make_segs(&mut out, &base_indent);
+        (out, base_indent, root)
+    };
+    let (mut out, base_indent, root) = {
+        let (mut out, base_indent, root) = build(comments, vec![]);
+
+        // Comments that couldn't be placed are moved to the start of the statement containing them
+        // while building, if any weren't in a statement build again with them moved somewhere they
+        // can go
+        if out.comments.keys().any(|k| k.0.line != 0) {
+            reattach_lost_comments(&mut out);
+            let mut comments = std::mem::take(&mut out.placed_comments);
+            comments.extend(out.comments.drain());
+            build(comments, out.moved_comments)
+        } else {
+            (out, base_indent, root)
+        }
     };
-    let base_indent = Alignment(Rc::new(RefCell::new(Alignment_ {
-        parent: None,
-        active: false,
-    })));
-    let root = ast.make_segs(&mut out, &base_indent);
     if out.comments.contains_key(&HashLineColumn(LineColumn {
         line: 0,
         column: 1,
@@ -549,38 +934,6 @@ pub fn format_ast(
         });
         sg.build(&mut out);
     }
-    let mut lines = Lines {
-        lines: vec![],
-        owned_lines: vec![],
-    };
-    {
-        let line_i = LineIdx(lines.owned_lines.len());
-        lines.owned_lines.push(Line {
-            index: 0,
-            segs: out.segs.iter().enumerate().map(|(i, _)| SegmentIdx(i)).collect(),
-        });
-        lines.lines.push(line_i);
-        for line_i in &lines.lines {
-            for (j, seg_i) in lines.owned_lines.get(line_i.0).unwrap().segs.iter().enumerate() {
-                out.segs.get_mut(seg_i.0).unwrap().line = Some(SegmentLine {
-                    line: *line_i,
-                    seg_index: j,
-                });
-            }
-        }
-    }
-    for (i, line_i) in lines.lines.iter().enumerate() {
-        let line = lines.owned_lines.get(line_i.0).unwrap();
-        assert_eq!(line.index, i, "line index wrong; initial");
-        for (j, seg_i) in line.segs.iter().enumerate() {
-            assert_eq!(
-                out.segs.get(seg_i.0).unwrap().line.as_ref().unwrap().seg_index,
-                j,
-                "seg index wrong; on line {}, initial",
-                i
-            );
-        }
-    }
 
     // Do initial splits
     //
@@ -589,75 +942,81 @@ pub fn format_ast(
     // * always split break segments
     //
     // * comments segments
+    //
+    // Lines are built in a single pass over the segments rather than by repeatedly
+    // splitting one giant line.
+    let mut lines = Lines {
+        owned_lines: vec![],
+        first: None,
+        debug_checks: config.debug_checks,
+    };
     {
         let synth_seg_node = new_sg(&mut out).build(&mut out);
-        let mut i = 0usize;
-        let mut skip_first = false;
+        let mut line_segs: Vec<Vec<SegmentIdx>> = vec![vec![]];
         let mut prev_comment = None;
-        while i < lines.lines.len() {
-            let mut res = None;
-            {
-                let line_i = lines.lines.get(i).unwrap();
-                'segs : loop {
-                    for (i, seg_i) in lines.owned_lines.get(line_i.0).unwrap().segs.iter().enumerate() {
-                        if i == 0 && skip_first {
-                            skip_first = false;
-                            continue;
-                        }
-                        let seg = out.segs.get(seg_i.0).unwrap();
-                        let node = out.nodes.get(seg.node.0).unwrap();
-                        match (&seg.content, match (&seg.mode, node.split) {
-                            (SegmentMode::All, true) => true,
-                            (SegmentMode::All, false) => true,
-                            (SegmentMode::Unsplit, true) => false,
-                            (SegmentMode::Unsplit, false) => true,
-                            (SegmentMode::Split, true) => true,
-                            (SegmentMode::Split, false) => false,
-                        }) {
-                            (SegmentContent::Break(_, _), true) => {
-                                res = Some((*line_i, i, None));
-                                prev_comment = None;
-                                break 'segs;
-                            },
-                            (SegmentContent::Comment(c), _) => {
-                                res = Some((*line_i, i, None));
-                                prev_comment = Some(c.0.clone());
-                                break 'segs;
-                            },
-                            (_, _) => {
-                                if let Some(a) = prev_comment.take() {
-                                    let seg_i = SegmentIdx(out.segs.len());
-                                    out.segs.push(Segment {
-                                        node: synth_seg_node,
-                                        line: None,
-                                        mode: SegmentMode::All,
-                                        content: SegmentContent::Break(a, true),
-                                    });
-                                    res = Some((*line_i, i, Some(seg_i)));
-                                    break 'segs;
-                                }
-                            },
-                        };
-                    }
-                    prev_comment = None;
-                    break;
+        for seg_i in (0 .. out.segs.len()).map(SegmentIdx) {
+            let trigger = {
+                let seg = out.segs.get(seg_i.0).unwrap();
+                let node = out.nodes.get(seg.node.0).unwrap();
+                match (&seg.content, match (&seg.mode, node.split) {
+                    (SegmentMode::All, true) => true,
+                    (SegmentMode::All, false) => true,
+                    (SegmentMode::Unsplit, true) => false,
+                    (SegmentMode::Unsplit, false) => true,
+                    (SegmentMode::Split, true) => true,
+                    (SegmentMode::Split, false) => false,
+                }) {
+                    (SegmentContent::Break(_, _), true) => Some(None),
+                    (SegmentContent::Comment(c), _) => Some(Some(c.0)),
+                    (_, _) => None,
                 }
+            };
+            match trigger {
+                Some(comment) => {
+                    prev_comment = comment;
+                    activate_line_start(&mut out, seg_i);
+                    if !line_segs.last().unwrap().is_empty() {
+                        line_segs.push(vec![]);
+                    }
+                },
+                None => {
+                    if let Some(a) = prev_comment.take() {
+                        let synth_seg_i = SegmentIdx(out.segs.len());
+                        out.segs.push(Segment {
+                            node: synth_seg_node,
+                            line: None,
+                            mode: SegmentMode::All,
+                            content: SegmentContent::Break(a, true),
+                        });
+                        activate_line_start(&mut out, synth_seg_i);
+                        line_segs.push(vec![synth_seg_i]);
+                    }
+                },
             }
-            if let Some((line_i, at, insert_start)) = res {
-                split_line_at(&mut out, &mut lines, line_i, at, insert_start);
-                skip_first = true;
-            }
-            i += 1;
+            line_segs.last_mut().unwrap().push(seg_i);
+        }
+        let mut prev = None;
+        for segs in line_segs {
+            prev = Some(push_line(&mut out, &mut lines, prev, None, segs));
+        }
+        if lines.debug_checks {
+            check_lines(&out, &lines, "initial");
         }
     }
 
     // Do width based splitting, other splitting
     fn recurse(out: &mut MakeSegsState, lines: &mut Lines, config: &FormatConfig, sg_i: SplitGroupIdx) -> bool {
         let mut split = false;
+        let mut last_line = None;
         for seg_i in &out.nodes.get(sg_i.0).unwrap().segments {
-            let seg = out.segs.get(seg_i.0).unwrap();
-            let len = line_length(out, lines, seg.line.as_ref().unwrap().line);
-            if len > config.max_width {
+            let line_i = out.segs.get(seg_i.0).unwrap().line.as_ref().unwrap().line;
+
+            // Consecutive segments are usually on the same line, only measure it once
+            if last_line == Some(line_i) {
+                continue;
+            }
+            last_line = Some(line_i);
+            if line_length(out, lines, line_i) > config.max_width {
                 split = true;
                 break;
             }
@@ -681,35 +1040,19 @@ pub fn format_ast(
     // Render
     let mut rendered = String::new();
     let lines = lines;
-    let mut line_i_i = 0usize;
-    while line_i_i < lines.lines.len() {
+    let mut at_line = lines.first;
+
+    // Inline comments moved from the end of a line to the start of the next
+    let mut pending_inline: Option<String> = None;
+    while let Some(line_i) = at_line {
+        let line = lines.owned_lines.get(line_i.0).unwrap();
+        let comment_bool =
+            line.next.map(|i| lines.owned_lines.get(i.0).unwrap()).and_then(|l| l.segs.first()).map(|seg_i| {
+                let seg = out.segs.get(seg_i.0).unwrap();
+                matches!(&seg.content, SegmentContent::Comment(_))
+            }).unwrap_or(false);
         'continue_lineloop : loop {
-            let segs =
-                lines
-                    .owned_lines
-                    .get(lines.lines.get(line_i_i).unwrap().0)
-                    .unwrap()
-                    .segs
-                    .iter()
-                    .filter_map(|seg_i| {
-                        let res = {
-                            let seg = out.segs.get(seg_i.0).unwrap();
-                            let node = out.nodes.get(seg.node.0).unwrap();
-                            match (&seg.mode, node.split) {
-                                (SegmentMode::All, _) => true,
-                                (SegmentMode::Unsplit, true) => false,
-                                (SegmentMode::Unsplit, false) => true,
-                                (SegmentMode::Split, true) => true,
-                                (SegmentMode::Split, false) => false,
-                            }
-                        };
-                        if res {
-                            Some(*seg_i)
-                        } else {
-                            None
-                        }
-                    })
-                    .collect::<Vec<SegmentIdx>>();
+            let segs = line.segs.iter().copied().filter(|seg_i| seg_visible(&out, *seg_i)).collect::<Vec<_>>();
             if segs.is_empty() {
                 break 'continue_lineloop;
             }
@@ -717,109 +1060,288 @@ pub fn format_ast(
                 let seg = out.segs.get(seg_i.0).unwrap();
                 match &seg.content {
                     SegmentContent::Text(t) => {
-                        let t = if seg_i_i == 1 {
-                            // Work around comments splitting lines at weird places
-                            t.trim_start()
-                        } else {
-                            t
-                        };
+                        let t =
+                            if seg_i_i == 1 &&
+                                matches!(out.segs.get(segs[0].0).unwrap().content, SegmentContent::Break(..)) {
+                                // Work around comments splitting lines at weird places
+                                t.trim_start()
+                            } else {
+                                t
+                            };
+                        if let Some(pending) = pending_inline.take() {
+                            rendered.push_str(&pending);
+                            if !t.starts_with(|c: char| c.is_whitespace() || ")]>,;.?".contains(c)) {
+                                rendered.push(' ');
+                            }
+                        }
                         rendered.push_str(t);
                     },
+                    SegmentContent::InlineComment(t) => {
+                        // At the end of a line it'd be read back as a comment on its own line, so move it to the next, unless it's
+                        // after an element and the next line closes the list, which keeps it
+                        let after_element =
+                            segs[..seg_i_i]
+                                .iter()
+                                .any(
+                                    |seg_i| !matches!(
+                                        out.segs.get(seg_i.0).unwrap().content,
+                                        SegmentContent::Break(..)
+                                    ),
+                                );
+                        let before_close =
+                            line.next.map(|i| line_starts_with_close(&out, lines.owned_lines.get(i.0).unwrap()));
+                        if seg_i_i + 1 == segs.len() && line.next.is_some() &&
+                            !(after_element && before_close.unwrap_or(false)) {
+                            pending_inline = Some(match pending_inline.take() {
+                                Some(pending) => format!("{} {}", pending, t),
+                                None => t.clone(),
+                            });
+                            continue;
+                        }
+
+                        // Separate from neighboring tokens with spaces except where brackets or punctuation attach
+                        let attaches = rendered.ends_with(|c: char| c.is_whitespace() || "([{<".contains(c));
+                        if !rendered.is_empty() && !attaches {
+                            rendered.push(' ');
+                        }
+                        if let Some(pending) = pending_inline.take() {
+                            rendered.push_str(&pending);
+                            rendered.push(' ');
+                        }
+                        rendered.push_str(t);
+                        let space_after =
+                            segs.get(seg_i_i + 1).map(|seg_i| match &out.segs.get(seg_i.0).unwrap().content {
+                                SegmentContent::Text(t) => !t.starts_with(
+                                    |c: char| c.is_whitespace() || ")]>,;.?".contains(c),
+                                ),
+                                SegmentContent::InlineComment(_) => true,
+                                _ => false,
+                            }).unwrap_or(false);
+                        if space_after {
+                            rendered.push(' ');
+                        }
+                    },
                     SegmentContent::Break(b, activate) => {
-                        let comment_bool =
-                            lines
-                                .lines
-                                .get(line_i_i + 1)
-                                .map(|i| lines.owned_lines.get(i.0).unwrap())
-                                .and_then(|l| l.segs.first())
-                                .map(|seg_i| {
-                                    let seg = out.segs.get(seg_i.0).unwrap();
-                                    matches!(&seg.content, SegmentContent::Comment(_))
-                                })
-                                .unwrap_or(false);
+                        let (b, activate) = (*b, *activate);
 
                         // since comments are always new lines we end up with duped newlines sometimes if there's a (break),
                         // (comment) on consec lines. skip the break
                         if segs.len() == 1 && comment_bool {
                             break 'continue_lineloop;
                         }
-                        if *activate {
-                            b.activate();
+                        if activate {
+                            b.activate(&mut out);
                         }
                         if segs.len() > 1 {
                             // if empty line (=just break), don't write indent
-                            rendered.push_str(&" ".repeat(b.get()));
+                            rendered.push_str(&" ".repeat(b.get(&out)));
                         }
                     },
-                    SegmentContent::Comment((b, comments)) => {
-                        for (i, comment) in comments.iter().enumerate() {
-                            if i > 0 {
-                                rendered.push('\n');
+                    SegmentContent::Comment((b, loc)) => {
+                        let mut previous = None;
+                        let comments = out.placed_comments.get(loc).unwrap().iter().filter(|c| !c.inline);
+                        for (i, extracted) in comments.enumerate() {
+                            if i == 0 && append_trailing_comment(&mut rendered, config.max_width, extracted) {
+                                continue;
                             }
-                            let prefix = format!("{}//{} ", " ".repeat(b.get()), match comment.mode {
+                            let comment = &extracted.comment;
+
+                            // `//.` has no block form, but code found in a block comment can go back in one
+                            let verbatim_line = comment.mode == CommentMode::Verbatim && extracted.style == CommentStyle::Line;
+                            let mut block =
+                                !verbatim_line &&
+                                    config.comment_style.unwrap_or(extracted.style) == CommentStyle::Block;
+                            let indent = " ".repeat(b.get(&out));
+                            let line_prefix = format!("{}//{} ", indent, match comment.mode {
                                 CommentMode::Normal => "",
                                 CommentMode::DocInner => "!",
                                 CommentMode::DocOuter => "/",
                                 CommentMode::Verbatim => ".",
                             });
-                            let verbatim = match comment.mode {
-                                CommentMode::Verbatim => {
-                                    true
-                                },
-                                _ => {
-                                    match format_md(
-                                        &mut rendered,
-                                        config.max_width,
-                                        config.comment_width,
-                                        &prefix,
-                                        &comment.lines,
-                                    ) {
-                                        Err(e) => {
-                                            let message =
-                                                format!(
-                                                    "Error formatting comments before {}:{}: \n{}",
-                                                    comment.loc.line,
-                                                    comment.loc.column,
-                                                    comment.lines
-                                                );
-                                            if config.comment_errors_fatal {
-                                                return Err(e.context(message));
-                                            } else if !config.quiet {
-                                                print_error_text();
-                                                eprintln!("{:?}", e.context(message));
-                                            }
-                                            true
-                                        },
-                                        Ok(_) => {
-                                            false
-                                        },
-                                    }
-                                },
+                            let mut prefix = if block && comment.mode == CommentMode::Verbatim {
+                                format!("{}   ", indent)
+                            } else if block {
+                                format!("{} * ", indent)
+                            } else {
+                                line_prefix.clone()
                             };
-                            if verbatim {
-                                for (i, line) in comment.lines.lines().enumerate() {
-                                    if i > 0 {
+                            let mut body = render_comment_body(config, comment, &prefix)?;
+
+                            // Text that would end a block comment early has to stay in a line comment
+                            if block && (body.contains("/*") || body.contains("*/")) {
+                                block = false;
+                                prefix = line_prefix;
+                                body = render_comment_body(config, comment, &prefix)?;
+                            }
+                            if previous.is_some() {
+                                rendered.push('\n');
+                            }
+
+                            // Keep separate comments apart so they aren't merged into one paragraph when reformatted
+                            let line_after_line = !block && previous == Some((comment.mode, false));
+                            if line_after_line && comment.mode != CommentMode::Verbatim {
+                                rendered.push_str(prefix.trim_end());
+                                rendered.push('\n');
+                            }
+                            previous = Some((comment.mode, block));
+                            if block {
+                                let opener = format!("{}/*{}", indent, match comment.mode {
+                                    CommentMode::DocInner => "!",
+                                    CommentMode::DocOuter => "*",
+                                    _ => "",
+                                });
+
+                                // Short comments go on one line, `/* text */`
+                                let one_line =
+                                    body
+                                        .strip_prefix(&prefix)
+                                        .filter(|text| !text.contains('\n'))
+                                        .map(|text| format!("{} {} */", opener, text))
+                                        .filter(|line| line.chars().count() <= config.max_width);
+                                match one_line {
+                                    Some(line) => rendered.push_str(&line),
+                                    None => {
+                                        rendered.push_str(&opener);
                                         rendered.push('\n');
-                                    }
-                                    let line = line.strip_prefix(' ').unwrap_or(line);
-                                    rendered.push_str(&format!("{}{}", prefix, line.trim_end()));
+                                        if !body.is_empty() {
+                                            rendered.push_str(&body);
+                                            rendered.push('\n');
+                                        }
+                                        rendered.push_str(&indent);
+                                        rendered.push_str(" */");
+                                    },
                                 }
+                            } else {
+                                rendered.push_str(&body);
                             }
                         }
+                        if previous.is_none() {
+                            break 'continue_lineloop;
+                        }
                     },
                 }
             }
+
+            // A comment always starts a line, even after a separator, which is left at the end of this one
+            if comment_bool {
+                rendered.truncate(rendered.trim_end_matches(' ').len());
+            }
             rendered.push('\n');
             break;
         }
-        line_i_i += 1;
+        at_line = line.next;
     }
     Ok(FormatRes {
         rendered,
-        lost_comments: out.comments,
+        lost_comments: out
+            .comments
+            .into_iter()
+            .map(|(k, v)| (k, v.into_iter().map(|c| c.comment).collect()))
+            .collect(),
+        moved_comments: out.moved_comments,
     })
 }
 
+/// Moves comments that weren't placed when building segments and aren't inside a
+/// statement to the closest token before them that supports comments.
+fn reattach_lost_comments(out: &mut MakeSegsState) {
+    let mut lost = out.comments.keys().map(|k| k.0).filter(|l| l.line != 0).collect::<Vec<LineColumn>>();
+    lost.sort_by_key(loc_key);
+    let mut anchors = out.comment_anchors.clone();
+    anchors.sort_by_key(loc_key);
+    for loc in lost {
+        let target =
+            anchors.iter().rev().find(|a| loc_key(a) <= loc_key(&loc)).or_else(|| anchors.first()).copied();
+        out.move_comments(loc, target);
+    }
+}
+
+/// Formats the text of a comment with `prefix` at the start of each line, or writes
+/// it as is if it's verbatim or can't be formatted.
+fn render_comment_body(config: &FormatConfig, comment: &Comment, prefix: &str) -> Result<String> {
+    let mut body = String::new();
+    let verbatim = match comment.mode {
+        CommentMode::Verbatim => {
+            true
+        },
+        _ => {
+            // Doc comment examples are Rust
+            let code_config = match comment.mode {
+                CommentMode::DocInner | CommentMode::DocOuter => Some(config),
+                _ => None,
+            };
+            match format_md_with_code(
+                &mut body,
+                config.max_width,
+                config.comment_width,
+                prefix,
+                &comment.lines,
+                code_config,
+            ) {
+                Err(e) => {
+                    let message =
+                        format!(
+                            "Error formatting comments before {}:{}: \n{}",
+                            comment.loc.line,
+                            comment.loc.column,
+                            comment.lines
+                        );
+                    if config.comment_errors_fatal {
+                        return Err(e.context(message));
+                    } else if !config.quiet {
+                        print_error_text();
+                        eprintln!("{:?}", e.context(message));
+                    }
+                    true
+                },
+                Ok(_) => {
+                    false
+                },
+            }
+        },
+    };
+    if verbatim {
+        body.clear();
+        for (i, line) in comment.lines.lines().enumerate() {
+            if i > 0 {
+                body.push('\n');
+            }
+            let line = line.strip_prefix(' ').unwrap_or(line);
+            body.push_str(format!("{}{}", prefix, line).trim_end());
+        }
+    }
+    Ok(body)
+}
+
+/// Adds a trailing comment to the end of the last line of code in `rendered`, if
+/// there is one and the comment fits after it. Returns whether it was added.
+fn append_trailing_comment(rendered: &mut String, max_width: usize, comment: &ExtractedComment) -> bool {
+    if !comment.trailing {
+        return false;
+    }
+    let prefix = match comment.comment.mode {
+        CommentMode::Normal => "//",
+        CommentMode::Verbatim => "//.",
+        _ => return false,
+    };
+    let end = rendered.trim_end().len();
+    let line_start = rendered[..end].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line = rendered[line_start .. end].trim_start();
+    if line.is_empty() || line.starts_with("//") || line.starts_with("/*") || line == "*/" {
+        return false;
+    }
+    let text = match comment.style {
+        // Block comment text doesn't keep the space after the opening `/*`
+        CommentStyle::Block => format!(" {} {}", prefix, comment.comment.lines.trim_end()),
+        CommentStyle::Line => format!(" {}{}", prefix, comment.comment.lines.trim_end()),
+    };
+    if rendered[line_start .. end].chars().count() + text.chars().count() > max_width {
+        return false;
+    }
+    rendered.insert_str(end, &text);
+    true
+}
+
 pub fn print_error_text() {
     // bold red
     eprint!("\x1B[1;38;5;9m");
@@ -829,6 +1351,15 @@ pub fn print_error_text() {
     eprint!("\x1B[0;22m");
 }
 
+pub fn print_warning_text() {
+    // bold yellow
+    eprint!("\x1B[1;33m");
+    eprint!("     Warning ");
+
+    // reset
+    eprint!("\x1B[0;22m");
+}
+
 pub fn print_skipping_text() {
     // bold red
     eprint!("\x1B[1;33m");
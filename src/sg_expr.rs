@@ -99,19 +99,32 @@ fn new_sg_dotted(out: &mut MakeSegsState, base_indent: &Alignment, root: Dotted)
             Dotted::Await(x) => {
                 new_sg_lit(out, Some((base_indent, x.dot_token.span.start())), ".await")
             },
-            Dotted::Field(e) => new_sg_lit(
-                out,
-                Some((base_indent, e.dot_token.span.start())),
-                format!(".{}", match &e.member {
-                    syn::Member::Named(n) => n.to_string(),
-                    syn::Member::Unnamed(u) => u.index.to_string(),
-                }),
-            ),
+            Dotted::Field(e) => {
+                let mut sg = new_sg(out);
+                append_comments(out, base_indent, &mut sg, e.dot_token.span.start());
+                sg.seg(out, ".");
+                match &e.member {
+                    syn::Member::Named(n) => {
+                        append_comments(out, base_indent, &mut sg, n.span().start());
+                        sg.seg(out, n);
+                    },
+                    syn::Member::Unnamed(u) => {
+                        append_comments(out, base_indent, &mut sg, u.span.start());
+                        sg.seg(out, u.index);
+                    },
+                }
+                sg.build(out)
+            },
             Dotted::Method(e) => {
                 let mut sg = new_sg(out);
                 sg.child({
-                    let build_base = |out: &mut MakeSegsState, _base_indent: &Alignment| {
-                        new_sg_lit(out, Some((base_indent, e.dot_token.span.start())), format!(".{}", e.method))
+                    let build_base = |out: &mut MakeSegsState, base_indent: &Alignment| {
+                        let mut sg = new_sg(out);
+                        append_comments(out, base_indent, &mut sg, e.dot_token.span.start());
+                        sg.seg(out, ".");
+                        append_comments(out, base_indent, &mut sg, e.method.span().start());
+                        sg.seg(out, &e.method);
+                        sg.build(out)
                     };
                     if let Some(tf) = &e.turbofish {
                         let mut sg = new_sg(out);
@@ -156,9 +169,9 @@ fn new_sg_dotted(out: &mut MakeSegsState, base_indent: &Alignment, root: Dotted)
     let mut sg = new_sg(out);
     sg.child(leaf.make_segs(out, base_indent));
     if children.len() > 1 {
-        let indent = base_indent.indent();
+        let indent = base_indent.indent(out);
         for child in children {
-            sg.split(out, indent.clone(), true);
+            sg.split(out, indent, true);
             sg.child(build_child(out, &indent, &child));
         }
     } else {
@@ -455,6 +468,7 @@ impl Formattable for &Expr {
                             sg.child(e.pat.make_segs(out, base_indent));
                             sg.build(out)
                         });
+                        append_comments(out, base_indent, &mut sg, e.in_token.span.start());
                         sg.seg(out, " in ");
                         sg.child(e.expr.make_segs(out, base_indent));
                         sg.build(out)
@@ -537,6 +551,7 @@ impl Formattable for &Expr {
                     append_comments(out, base_indent, &mut sg, e.let_token.span.start());
                     sg.seg(out, "let ");
                     sg.child(e.pat.make_segs(out, base_indent));
+                    append_comments(out, base_indent, &mut sg, e.eq_token.span.start());
                     append_binary(out, base_indent, &mut sg, " =", e.expr.as_ref());
                     sg.build(out)
                 },
@@ -597,11 +612,12 @@ impl Formattable for &Expr {
                     append_comments(out, base_indent, &mut sg, e.match_token.span.start());
                     sg.seg(out, "match ");
                     sg.child(e.expr.make_segs(out, base_indent));
+                    append_comments(out, base_indent, &mut sg, e.brace_token.span.start());
                     sg.seg(out, " {");
                     sg.seg_unsplit(out, " ");
-                    let indent = base_indent.indent();
+                    let indent = base_indent.indent(out);
                     for (i, arm) in e.arms.iter().enumerate() {
-                        sg.split(out, indent.clone(), true);
+                        sg.split(out, indent, true);
                         sg.child(
                             new_sg_outer_attrs(
                                 out,
@@ -631,6 +647,9 @@ impl Formattable for &Expr {
                                 },
                             ),
                         );
+                        if let Some(c) = &arm.comma {
+                            append_comments(out, base_indent, &mut sg, c.span.start());
+                        }
                         if i == e.arms.len() - 1 {
                             sg.seg_split(out, ",");
                         } else {
@@ -638,7 +657,7 @@ impl Formattable for &Expr {
                         }
                         sg.seg_unsplit(out, " ");
                     }
-                    sg.split(out, base_indent.clone(), false);
+                    sg.split(out, *base_indent, false);
                     append_comments(out, base_indent, &mut sg, e.brace_token.span.end().prev());
                     sg.seg(out, "}");
                     sg.build(out)
@@ -694,8 +713,8 @@ impl Formattable for &Expr {
                         },
                         (Some(l), None) => {
                             let mut sg = new_sg(out);
-                            append_comments(out, base_indent, &mut sg, tok_loc);
                             sg.child(l.as_ref().make_segs(out, base_indent));
+                            append_comments(out, base_indent, &mut sg, tok_loc);
                             sg.seg(out, tok);
                             sg.build(out)
                         },
@@ -715,7 +734,13 @@ impl Formattable for &Expr {
                 base_indent,
                 &e.attrs,
                 |out: &mut MakeSegsState, base_indent: &Alignment| {
-                    build_ref(out, base_indent, e.and_token.span.start(), e.mutability.is_some(), e.expr.as_ref())
+                    build_ref(
+                        out,
+                        base_indent,
+                        e.and_token.span.start(),
+                        e.mutability.map(|m| m.span.start()),
+                        e.expr.as_ref(),
+                    )
                 },
             ),
             Expr::Repeat(e) => new_sg_outer_attrs(
@@ -723,7 +748,15 @@ impl Formattable for &Expr {
                 base_indent,
                 &e.attrs,
                 |out: &mut MakeSegsState, base_indent: &Alignment| {
-                    build_array_type(out, base_indent, e.bracket_token.span.start(), e.expr.as_ref(), e.len.as_ref())
+                    build_array_type(
+                        out,
+                        base_indent,
+                        e.bracket_token.span.start(),
+                        e.expr.as_ref(),
+                        e.semi_token.span.start(),
+                        e.len.as_ref(),
+                        e.bracket_token.span.end().prev(),
+                    )
                 },
             ),
             Expr::Return(e) => new_sg_outer_attrs(
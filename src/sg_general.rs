@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     fmt::Write,
 };
 use proc_macro2::{
@@ -34,6 +35,7 @@ use crate::{
     new_sg,
     sg_type::build_path,
     Alignment,
+    ExtractedComment,
     Formattable,
     FormattableStmt,
     MakeSegsState,
@@ -71,8 +73,8 @@ pub(crate) fn append_binary(
     right: impl Formattable,
 ) {
     node.seg(out, tok);
-    let indent = base_indent.indent();
-    node.split(out, indent.clone(), true);
+    let indent = base_indent.indent(out);
+    node.split(out, indent, true);
     node.seg_unsplit(out, " ");
     node.child(right.make_segs(out, &indent));
 }
@@ -102,7 +104,7 @@ pub(crate) fn append_attr(
     append_comments(out, base_indent, sg, attr.pound_token.span.start());
     sg.child({
         let mut sg = new_sg(out);
-        let indent = base_indent.indent();
+        let indent = base_indent.indent(out);
         let mut prefix = String::new();
         prefix.write_str(if bang {
             "#!["
@@ -123,10 +125,21 @@ pub(crate) fn append_statement_list_raw(
     base_indent: &Alignment,
     sg: &mut SplitGroupBuilder,
     attrs: Option<&Vec<Attribute>>,
-    block: &Vec<impl FormattableStmt>,
+    block: &[impl FormattableStmt],
 ) {
+    let starts =
+        block
+            .iter()
+            .map(|s| s.to_token_stream().into_iter().next().map(|t| t.span().start()))
+            .collect::<Vec<Option<LineColumn>>>();
     if check_split_brace_threshold(out, block.len()) ||
-        block.iter().any(|s| has_comments(out, s) || (s.has_attrs() && out.split_attributes)) {
+        block
+            .iter()
+            .zip(&starts)
+            .any(
+                |(s, start)| start.map(|start| has_comments_at(out, start)).unwrap_or(false) ||
+                    (s.has_attrs() && out.split_attributes),
+            ) {
         sg.initial_split();
     }
     sg.seg_unsplit(out, " ");
@@ -140,7 +153,7 @@ pub(crate) fn append_statement_list_raw(
             syn::AttrStyle::Inner(_) => { },
         };
         if i > 0 {
-            sg.split_if(out, base_indent.clone(), out.split_attributes, false);
+            sg.split_if(out, *base_indent, out.split_attributes, false);
         }
         append_attr(out, base_indent, sg, true, attr);
         if !out.split_attributes {
@@ -149,21 +162,83 @@ pub(crate) fn append_statement_list_raw(
         previous_margin_group = MarginGroup::Attr;
         i += 1;
     }
-    for el in block {
-        let (new_margin_group, want_margin) = el.want_margin();
-        if i > 0 {
-            if previous_margin_group != new_margin_group || want_margin || has_comments(out, el) {
-                sg.split(out, base_indent.clone(), true);
+    for (el, start) in block.iter().zip(starts) {
+        let (new_margin_group, _) = el.want_margin();
+        append_statement(out, base_indent, sg, start, el, |out, sg| {
+            if i > 0 {
+                if want_statement_margin(&out.comments, &previous_margin_group, el, start) {
+                    sg.split(out, *base_indent, true);
+                }
+                sg.split(out, *base_indent, true);
             }
-            sg.split(out, base_indent.clone(), true);
-        }
-        sg.child((el).make_segs(out, base_indent));
+        });
         sg.seg_unsplit(out, " ");
         previous_margin_group = new_margin_group;
         i += 1;
     }
 }
 
+/// Whether to leave a blank line between `el` and the statement before it, in
+/// `previous`, to set apart different kinds of statements or comments before `el`.
+pub(crate) fn want_statement_margin(
+    comments: &HashMap<HashLineColumn, Vec<ExtractedComment>>,
+    previous: &MarginGroup,
+    el: &impl FormattableStmt,
+    start: Option<LineColumn>,
+) -> bool {
+    let (new_margin_group, want_margin) = el.want_margin();
+    *previous != new_margin_group || want_margin ||
+        start
+            .and_then(|start| comments.get(&HashLineColumn(start)))
+            .map(|c| c.iter().any(|c| c.is_leading()))
+            .unwrap_or(false)
+}
+
+/// Appends a statement starting at `start` after the segments from `before`. Some
+/// tokens don't support comments, if there were any before them in the statement it's
+/// built again with the comments moved to its start.
+pub(crate) fn append_statement(
+    out: &mut MakeSegsState,
+    base_indent: &Alignment,
+    sg: &mut SplitGroupBuilder,
+    start: Option<LineColumn>,
+    el: &impl Formattable,
+    before: impl Fn(&mut MakeSegsState, &mut SplitGroupBuilder),
+) {
+    // Building only appends, so this is everything needed to undo it
+    let segs = out.segs.len();
+    let nodes = out.nodes.len();
+    let alignments = out.alignments.len();
+    let anchors = out.comment_anchors.len();
+    let sg_segs = sg.segs.len();
+    let sg_children = sg.children.len();
+    before(out, sg);
+    sg.child(el.make_segs(out, base_indent));
+    let start = match start {
+        Some(s) => s,
+        None => return,
+    };
+    let lost = out.unplaced_comments(start, &out.comment_anchors[anchors..]);
+    if lost.is_empty() || !out.comment_anchors[anchors..].contains(&start) {
+        return;
+    }
+    for anchor in out.comment_anchors.drain(anchors..) {
+        if let Some(comments) = out.placed_comments.remove(&HashLineColumn(anchor)) {
+            out.comments.insert(HashLineColumn(anchor), comments);
+        }
+    }
+    out.segs.truncate(segs);
+    out.nodes.truncate(nodes);
+    out.alignments.truncate(alignments);
+    sg.segs.truncate(sg_segs);
+    sg.children.truncate(sg_children);
+    for loc in lost {
+        out.move_comments(loc, Some(start));
+    }
+    before(out, sg);
+    sg.child(el.make_segs(out, base_indent));
+}
+
 pub(crate) fn append_bracketed_statement_list(
     out: &mut MakeSegsState,
     base_indent: &Alignment,
@@ -171,19 +246,19 @@ pub(crate) fn append_bracketed_statement_list(
     prefix_start: LineColumn,
     prefix: &'static str,
     attrs: Option<&Vec<Attribute>>,
-    stmts: &Vec<impl FormattableStmt>,
+    stmts: &[impl FormattableStmt],
     suffix_start: LineColumn,
 ) {
-    if out.comments.contains_key(&HashLineColumn(suffix_start)) {
+    if has_comments_at(out, suffix_start) {
         sg.initial_split();
     }
     append_comments(out, base_indent, sg, prefix_start);
     sg.seg(out, prefix);
-    let indent = base_indent.indent();
-    sg.split(out, indent.clone(), true);
+    let indent = base_indent.indent(out);
+    sg.split(out, indent, true);
     append_statement_list_raw(out, &indent, sg, attrs, stmts);
     append_comments(out, &indent, sg, suffix_start);
-    sg.split(out, base_indent.clone(), false);
+    sg.split(out, *base_indent, false);
     sg.seg(out, "}");
 }
 
@@ -193,7 +268,7 @@ pub(crate) fn new_sg_block(
     prefix_start: LineColumn,
     prefix: &'static str,
     attrs: Option<&Vec<Attribute>>,
-    block: &Vec<impl FormattableStmt>,
+    block: &[impl FormattableStmt],
     suffix_start: LineColumn,
 ) -> SplitGroupIdx {
     let mut sg = new_sg(out);
@@ -222,7 +297,7 @@ pub(crate) fn new_sg_outer_attrs(
         if !out.split_attributes {
             sg.seg_unsplit(out, " ");
         }
-        sg.split_if(out, base_indent.clone(), out.split_attributes, false);
+        sg.split_if(out, *base_indent, out.split_attributes, false);
     }
     sg.child(child.make_segs(out, base_indent));
     sg.build(out)
@@ -235,6 +310,11 @@ pub(crate) fn append_macro_bracketed(
     mac: &Macro,
     semi: bool,
 ) {
+    append_comments(out, base_indent, sg, match &mac.delimiter {
+        MacroDelimiter::Paren(x) => x.span.start(),
+        MacroDelimiter::Brace(x) => x.span.start(),
+        MacroDelimiter::Bracket(x) => x.span.start(),
+    });
     append_macro_body_bracketed(out, base_indent, sg, &mac.delimiter, mac.tokens.clone());
     if semi {
         sg.seg(out, ";");
@@ -249,6 +329,7 @@ pub(crate) fn new_sg_macro(
 ) -> SplitGroupIdx {
     let mut sg = new_sg(out);
     sg.child(build_path(out, base_indent, &mac.path));
+    append_comments(out, base_indent, &mut sg, mac.bang_token.span.start());
     sg.seg(out, "!");
     append_macro_bracketed(out, base_indent, &mut sg, mac, semi);
     sg.build(out)
@@ -261,37 +342,37 @@ pub(crate) fn append_macro_body_bracketed(
     delim: &MacroDelimiter,
     tokens: TokenStream,
 ) {
-    let indent = base_indent.indent();
+    let indent = base_indent.indent(out);
     match delim {
         syn::MacroDelimiter::Paren(x) => {
             sg.seg(out, "(");
             if !tokens.is_empty() || out.comments.contains_key(&HashLineColumn(x.span.end().prev())) {
-                sg.split(out, indent.clone(), true);
+                sg.split(out, indent, true);
                 append_macro_body(out, &indent, sg, tokens);
                 append_comments(out, base_indent, sg, x.span.end().prev());
             }
-            sg.split(out, base_indent.clone(), false);
+            sg.split(out, *base_indent, false);
             sg.seg(out, ")");
         },
         syn::MacroDelimiter::Brace(x) => {
             sg.seg(out, "{");
             sg.initial_split();
             if !tokens.is_empty() || out.comments.contains_key(&HashLineColumn(x.span.end().prev())) {
-                sg.split(out, indent.clone(), true);
+                sg.split(out, indent, true);
                 append_macro_body(out, &indent, sg, tokens);
                 append_comments(out, base_indent, sg, x.span.end().prev());
             }
-            sg.split(out, base_indent.clone(), false);
+            sg.split(out, *base_indent, false);
             sg.seg(out, "}");
         },
         syn::MacroDelimiter::Bracket(x) => {
             sg.seg(out, "[");
             if !tokens.is_empty() || out.comments.contains_key(&HashLineColumn(x.span.end().prev())) {
-                sg.split(out, indent.clone(), true);
+                sg.split(out, indent, true);
                 append_macro_body(out, &indent, sg, tokens);
                 append_comments(out, base_indent, sg, x.span.end().prev());
             }
-            sg.split(out, base_indent.clone(), false);
+            sg.split(out, *base_indent, false);
             sg.seg(out, "]");
         },
     }
@@ -382,7 +463,7 @@ pub(crate) fn append_macro_body(
     for (i, sub) in substreams.into_iter().enumerate() {
         'nextsub : loop {
             if i > 0 {
-                sg.split(out, base_indent.clone(), true);
+                sg.split(out, *base_indent, true);
             }
             let tokens = TokenStream::from_iter(sub.0);
             let punct = sub.1;
@@ -428,7 +509,7 @@ pub(crate) fn append_macro_body(
                             append_comments(out, base_indent, sg, g.span_open().start());
                             sg.child({
                                 let mut sg = new_sg(out);
-                                let indent = base_indent.indent();
+                                let indent = base_indent.indent(out);
                                 match g.delimiter() {
                                     proc_macro2::Delimiter::Parenthesis => {
                                         append_macro_body_bracketed(out, &indent, &mut sg, &MacroDelimiter::Paren({
@@ -544,25 +625,44 @@ pub(crate) fn append_comments(
     sg: &mut SplitGroupBuilder,
     loc: LineColumn,
 ) {
+    out.comment_anchors.push(loc);
     let comments = match out.comments.remove(&HashLineColumn(loc)) {
         Some(c) => c,
         None => return,
     };
-    sg.add(out, crate::Segment {
-        node: sg.node,
-        line: None,
-        mode: crate::SegmentMode::All,
-        content: crate::SegmentContent::Comment((base_indent.clone(), comments)),
-    });
+    let has_lines = comments.iter().any(|c| !c.inline);
+    let inline = comments.iter().filter(|c| c.inline).map(|c| if c.comment.lines.is_empty() {
+        "/* */".to_string()
+    } else {
+        format!("/* {} */", c.comment.lines)
+    }).collect::<Vec<String>>();
+    out.placed_comments.insert(HashLineColumn(loc), comments);
+    if has_lines {
+        sg.add(out, crate::Segment {
+            node: sg.node,
+            line: None,
+            mode: crate::SegmentMode::All,
+            content: crate::SegmentContent::Comment((*base_indent, HashLineColumn(loc))),
+        });
+    }
+    if !inline.is_empty() {
+        let text = inline.join(" ");
+        sg.add(out, crate::Segment {
+            node: sg.node,
+            line: None,
+            mode: crate::SegmentMode::All,
+            content: crate::SegmentContent::InlineComment(text),
+        });
+    }
+}
+
+/// Whether there are comments at `loc` that need their own lines (not inline comments).
+pub(crate) fn has_comments_at(out: &MakeSegsState, loc: LineColumn) -> bool {
+    out.comments.get(&HashLineColumn(loc)).map(|c| c.iter().any(|c| !c.inline)).unwrap_or(false)
 }
 
 pub(crate) fn has_comments(out: &mut MakeSegsState, t: impl ToTokens) -> bool {
-    t
-        .to_token_stream()
-        .into_iter()
-        .next()
-        .map(|t| out.comments.contains_key(&HashLineColumn(t.span().start())))
-        .unwrap_or(false)
+    t.to_token_stream().into_iter().next().map(|t| has_comments_at(out, t.span().start())).unwrap_or(false)
 }
 
 impl FormattablePunct for Comma {
@@ -24,10 +24,10 @@ use crate::{
     sg_general::{
         append_comments,
         has_comments,
+        has_comments_at,
     },
     SplitGroupIdx,
     new_sg,
-    comments::HashLineColumn,
 };
 
 pub(crate) enum InlineListSuffix<T: Formattable> {
@@ -60,14 +60,19 @@ pub(crate) fn append_inline_list_raw<
                 append_comments(out, base_indent, sg, p.span_start());
                 sg.seg(out, punct);
             }
-            sg.split(out, base_indent.clone(), true);
+            sg.split(out, *base_indent, true);
             sg.seg_unsplit(out, " ");
         }
         sg.child(pair.value().make_segs(out, base_indent));
         next_punct = pair.punct().copied();
     }
     match suffix {
-        InlineListSuffix::None => { },
+        InlineListSuffix::None => {
+            // The trailing punct is dropped but comments before it stay after the last element
+            if let Some(p) = next_punct {
+                append_comments(out, base_indent, sg, p.span_start());
+            }
+        },
         InlineListSuffix::UnitPunct if exprs.len() == 1 => {
             if let Some(p) = next_punct {
                 append_comments(out, base_indent, sg, p.span_start());
@@ -92,7 +97,7 @@ pub(crate) fn append_inline_list_raw<
                 sg.seg(out, punct);
             }
             if !exprs.is_empty() {
-                sg.split(out, base_indent.clone(), true);
+                sg.split(out, *base_indent, true);
                 sg.seg_unsplit(out, " ");
             }
             e.make_segs(out, base_indent);
@@ -112,8 +117,8 @@ pub(crate) fn append_inline_list<
     exprs: &Punctuated<E, T>,
     suffix: InlineListSuffix<F>,
 ) {
-    let indent = base_indent.indent();
-    sg.split(out, indent.clone(), true);
+    let indent = base_indent.indent(out);
+    sg.split(out, indent, true);
     append_inline_list_raw(out, &indent, sg, punct, exprs, suffix);
 }
 
@@ -134,7 +139,7 @@ pub(crate) fn append_bracketed_list<
     suffix_start: LineColumn,
     suffix: &str,
 ) {
-    if out.comments.contains_key(&HashLineColumn(suffix_start)) {
+    if has_comments_at(out, suffix_start) {
         sg.initial_split();
     }
     append_comments(out, base_indent, sg, prefix_start);
@@ -143,14 +148,14 @@ pub(crate) fn append_bracketed_list<
     if need_pad {
         sg.seg_unsplit(out, " ");
     }
-    let indent = base_indent.indent();
-    sg.split(out, indent.clone(), true);
+    let indent = base_indent.indent(out);
+    sg.split(out, indent, true);
     append_inline_list_raw(out, &indent, sg, punct, exprs, list_suffix);
     if need_pad {
         sg.seg_unsplit(out, " ");
     }
     append_comments(out, &indent, sg, suffix_start);
-    sg.split(out, base_indent.clone(), false);
+    sg.split(out, *base_indent, false);
     sg.seg(out, suffix);
 }
 
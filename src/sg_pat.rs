@@ -1,6 +1,3 @@
-use std::{
-    fmt::Write,
-};
 use syn::{
     Expr,
     FieldPat,
@@ -44,6 +41,7 @@ impl Formattable for &Pat {
                 &x.attrs,
                 |out: &mut MakeSegsState, base_indent: &Alignment| {
                     let mut node = new_sg(out);
+                    append_comments(out, base_indent, &mut node, x.box_token.span.start());
                     node.seg(out, "box ");
                     node.child(x.pat.as_ref().make_segs(out, base_indent));
                     node.build(out)
@@ -54,28 +52,24 @@ impl Formattable for &Pat {
                 base_indent,
                 &x.attrs,
                 |out: &mut MakeSegsState, base_indent: &Alignment| {
-                    let mut prefix = String::new();
-                    let mut start = None;
-                    if let Some(y) = x.by_ref {
-                        prefix.write_str("ref ").unwrap();
-                        start = Some(y.span.start());
-                    }
-                    if let Some(y) = x.mutability {
-                        prefix.write_str("mut ").unwrap();
-                        if start.is_none() {
-                            start = Some(y.span.start());
+                    let build_base = |out: &mut MakeSegsState, base_indent: &Alignment| {
+                        let mut sg = new_sg(out);
+                        if let Some(y) = &x.by_ref {
+                            append_comments(out, base_indent, &mut sg, y.span.start());
+                            sg.seg(out, "ref ");
                         }
-                    }
-                    prefix.write_str(&x.ident.to_string()).unwrap();
-                    if start.is_none() {
-                        start = Some(x.ident.span().start());
-                    }
+                        if let Some(y) = &x.mutability {
+                            append_comments(out, base_indent, &mut sg, y.span.start());
+                            sg.seg(out, "mut ");
+                        }
+                        append_comments(out, base_indent, &mut sg, x.ident.span().start());
+                        sg.seg(out, &x.ident);
+                        sg.build(out)
+                    };
                     if let Some(at) = &x.subpat {
-                        new_sg_binary(out, base_indent, |out: &mut MakeSegsState, base_indent: &Alignment| {
-                            new_sg_lit(out, start.map(|s| (base_indent, s)), &prefix)
-                        }, at.0.span.start(), " @", &*at.1)
+                        new_sg_binary(out, base_indent, build_base, at.0.span.start(), " @", &*at.1)
                     } else {
-                        new_sg_lit(out, start.map(|s| (base_indent, s)), prefix)
+                        build_base(out, base_indent)
                     }
                 },
             ),
@@ -141,7 +135,13 @@ impl Formattable for &Pat {
                 base_indent,
                 &x.attrs,
                 |out: &mut MakeSegsState, base_indent: &Alignment| {
-                    build_ref(out, base_indent, x.and_token.span.start(), x.mutability.is_some(), x.pat.as_ref())
+                    build_ref(
+                        out,
+                        base_indent,
+                        x.and_token.span.start(),
+                        x.mutability.map(|m| m.span.start()),
+                        x.pat.as_ref(),
+                    )
                 },
             ),
             Pat::Rest(x) => new_sg_outer_attrs(
@@ -244,7 +244,11 @@ impl Formattable for &Pat {
                     new_sg_binary(out, base_indent, x.pat.as_ref(), x.colon_token.span.start(), ":", x.ty.as_ref())
                 },
             ),
-            Pat::Verbatim(x) => new_sg_lit(out, None, x),
+            Pat::Verbatim(x) => new_sg_lit(
+                out,
+                x.clone().into_iter().next().map(|t| (base_indent, t.span().start())),
+                x,
+            ),
             Pat::Wild(x) => new_sg_outer_attrs(
                 out,
                 base_indent,
@@ -296,8 +300,14 @@ impl Formattable for FieldPat {
             let mut sg = new_sg(out);
             if let Some(col) = &self.colon_token {
                 match &self.member {
-                    syn::Member::Named(x) => sg.seg(out, x),
-                    syn::Member::Unnamed(x) => sg.seg(out, x.index),
+                    syn::Member::Named(x) => {
+                        append_comments(out, base_indent, &mut sg, x.span().start());
+                        sg.seg(out, x);
+                    },
+                    syn::Member::Unnamed(x) => {
+                        append_comments(out, base_indent, &mut sg, x.span.start());
+                        sg.seg(out, x.index);
+                    },
                 };
                 append_comments(out, base_indent, &mut sg, col.span.start());
                 sg.seg(out, ": ");
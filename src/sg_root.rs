@@ -24,8 +24,8 @@ impl Formattable for File {
         if let Some(shebang) = &self.shebang {
             let mut sg = new_sg(out);
             sg.seg(out, shebang);
-            sg.split_always(out, base_indent.clone(), true);
-            sg.split_always(out, base_indent.clone(), true);
+            sg.split_always(out, *base_indent, true);
+            sg.split_always(out, *base_indent, true);
             sg.child(build_inner(out, base_indent, self));
             sg.build(out)
         } else {
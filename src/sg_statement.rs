@@ -138,7 +138,8 @@ fn new_sg_sig(out: &mut MakeSegsState, base_indent: &Alignment, sig: &Signature)
     );
     match &sig.output {
         ReturnType::Default => { },
-        ReturnType::Type(_, t) => {
+        ReturnType::Type(arrow, t) => {
+            append_comments(out, base_indent, &mut sg, arrow.spans[0].start());
             sg.seg(out, " -> ");
             sg.child(t.make_segs(out, base_indent));
         },
@@ -173,6 +174,7 @@ impl Formattable for Stmt {
                     sg.seg(out, "let ");
                     sg.child(l.pat.make_segs(out, base_indent));
                     if let Some(init) = &l.init {
+                        append_comments(out, base_indent, &mut sg, init.0.span.start());
                         append_binary(out, base_indent, &mut sg, " =", init.1.as_ref());
                     }
                     append_comments(out, base_indent, &mut sg, l.semi_token.span.start());
@@ -255,8 +257,9 @@ impl Formattable for ForeignItem {
                     append_comments(out, base_indent, &mut sg, x.type_token.span.start());
                     let mut prefix = String::new();
                     prefix.push_str("type ");
-                    prefix.push_str(&x.ident.to_string());
                     sg.seg(out, &prefix);
+                    append_comments(out, base_indent, &mut sg, x.ident.span().start());
+                    sg.seg(out, &x.ident.to_string());
                     append_comments(out, base_indent, &mut sg, x.semi_token.span.start());
                     sg.seg(out, ";");
                     sg.build(out)
@@ -323,11 +326,14 @@ impl Formattable for ImplItem {
                         }
                         append_comments(out, base_indent, &mut sg, x.const_token.span.start());
                         prefix.push_str("const ");
-                        prefix.push_str(&x.ident.to_string());
                         sg.seg(out, &prefix);
+                        append_comments(out, base_indent, &mut sg, x.ident.span().start());
+                        sg.seg(out, &x.ident.to_string());
+                        append_comments(out, base_indent, &mut sg, x.colon_token.span.start());
                         append_binary(out, base_indent, &mut sg, ":", &x.ty);
                         sg.build(out)
                     });
+                    append_comments(out, base_indent, &mut sg, x.eq_token.span.start());
                     append_binary(out, base_indent, &mut sg, " =", &x.expr);
                     append_comments(out, base_indent, &mut sg, x.semi_token.span.start());
                     sg.seg(out, ";");
@@ -371,9 +377,11 @@ impl Formattable for ImplItem {
                     }
                     append_comments(out, base_indent, &mut sg, x.type_token.span.start());
                     prefix.push_str("type ");
-                    prefix.push_str(&x.ident.to_string());
                     sg.seg(out, &prefix);
+                    append_comments(out, base_indent, &mut sg, x.ident.span().start());
+                    sg.seg(out, &x.ident.to_string());
                     append_generics(out, base_indent, &mut sg, &x.generics);
+                    append_comments(out, base_indent, &mut sg, x.eq_token.span.start());
                     append_binary(out, base_indent, &mut sg, " =", &x.ty);
                     append_comments(out, base_indent, &mut sg, x.semi_token.span.start());
                     sg.seg(out, ";");
@@ -437,8 +445,10 @@ impl Formattable for TraitItem {
                             append_comments(out, base_indent, &mut sg, x.const_token.span.start());
                             let mut prefix = String::new();
                             prefix.push_str("const ");
-                            prefix.push_str(&x.ident.to_string());
                             sg.seg(out, &prefix);
+                            append_comments(out, base_indent, &mut sg, x.ident.span().start());
+                            sg.seg(out, &x.ident.to_string());
+                            append_comments(out, base_indent, &mut sg, x.colon_token.span.start());
                             append_binary(out, base_indent, &mut sg, ":", &x.ty);
                             sg.build(out)
                         };
@@ -493,8 +503,9 @@ impl Formattable for TraitItem {
                         append_comments(out, base_indent, &mut sg, x.type_token.span.start());
                         let mut prefix = String::new();
                         prefix.push_str("type ");
-                        prefix.push_str(&x.ident.to_string());
                         sg.seg(out, &prefix);
+                        append_comments(out, base_indent, &mut sg, x.ident.span().start());
+                        sg.seg(out, &x.ident.to_string());
                         append_generics(out, base_indent, &mut sg, &x.generics);
                         if let Some(c) = &x.colon_token {
                             append_comments(out, base_indent, &mut sg, c.span.start());
@@ -610,9 +621,12 @@ impl Formattable for Item {
                     append_vis(out, base_indent, &mut sg, &x.vis);
                     append_comments(out, base_indent, &mut sg, x.const_token.span.start());
                     sg.seg(out, "const ");
+                    append_comments(out, base_indent, &mut sg, x.ident.span().start());
                     sg.seg(out, &x.ident.to_string());
+                    append_comments(out, base_indent, &mut sg, x.colon_token.span.start());
                     sg.seg(out, ": ");
                     sg.child(x.ty.make_segs(out, base_indent));
+                    append_comments(out, base_indent, &mut sg, x.eq_token.span.start());
                     append_binary(out, base_indent, &mut sg, " =", x.expr.as_ref());
                     append_comments(out, base_indent, &mut sg, x.semi_token.span.start());
                     sg.seg(out, ";");
@@ -631,6 +645,7 @@ impl Formattable for Item {
                     append_vis(out, base_indent, &mut sg, &x.vis);
                     append_comments(out, base_indent, &mut sg, x.enum_token.span.start());
                     sg.seg(out, "enum ");
+                    append_comments(out, base_indent, &mut sg, x.ident.span().start());
                     sg.seg(out, &x.ident.to_string());
                     append_generics(out, base_indent, &mut sg, &x.generics);
                     append_bracketed_list_curly(
@@ -735,11 +750,13 @@ impl Formattable for Item {
                         sg.child(build_generics_part_a(out, base_indent, &x.generics));
                     }
                     sg.seg(out, " ");
-                    if let Some((bang, base, _)) = &x.trait_ {
-                        if bang.is_some() {
+                    if let Some((bang, base, for_token)) = &x.trait_ {
+                        if let Some(b) = bang {
+                            append_comments(out, base_indent, &mut sg, b.span.start());
                             sg.seg(out, "!");
                         }
                         sg.child(build_path(out, base_indent, base));
+                        append_comments(out, base_indent, &mut sg, for_token.span.start());
                         sg.seg(out, " for ");
                     }
                     sg.child(x.self_ty.make_segs(out, base_indent));
@@ -783,7 +800,7 @@ impl Formattable for Item {
                     append_vis(out, base_indent, &mut sg, &x.vis);
                     sg.seg(out, "macro ");
                     sg.seg(out, &x.ident.to_string());
-                    let indent = base_indent.indent();
+                    let indent = base_indent.indent(out);
                     append_macro_body(out, &indent, &mut sg, x.rules.clone());
                     sg.build(out)
                 },
@@ -797,6 +814,7 @@ impl Formattable for Item {
                     append_vis(out, base_indent, &mut sg, &x.vis);
                     append_comments(out, base_indent, &mut sg, x.mod_token.span.start());
                     sg.seg(out, "mod ");
+                    append_comments(out, base_indent, &mut sg, x.ident.span().start());
                     sg.seg(out, &x.ident.to_string());
                     if let Some(content) = &x.content {
                         append_bracketed_statement_list(
@@ -825,9 +843,12 @@ impl Formattable for Item {
                     append_vis(out, base_indent, &mut sg, &x.vis);
                     append_comments(out, base_indent, &mut sg, x.static_token.span.start());
                     sg.seg(out, "static ");
+                    append_comments(out, base_indent, &mut sg, x.ident.span().start());
                     sg.seg(out, &x.ident.to_string());
+                    append_comments(out, base_indent, &mut sg, x.colon_token.span.start());
                     sg.seg(out, ": ");
                     sg.child(x.ty.make_segs(out, base_indent));
+                    append_comments(out, base_indent, &mut sg, x.eq_token.span.start());
                     append_binary(out, base_indent, &mut sg, " =", x.expr.as_ref());
                     append_comments(out, base_indent, &mut sg, x.semi_token.span.start());
                     sg.seg(out, ";");
@@ -843,6 +864,7 @@ impl Formattable for Item {
                     append_vis(out, base_indent, &mut sg, &x.vis);
                     append_comments(out, base_indent, &mut sg, x.struct_token.span.start());
                     sg.seg(out, "struct ");
+                    append_comments(out, base_indent, &mut sg, x.ident.span().start());
                     sg.seg(out, &x.ident.to_string());
                     if !x.generics.params.is_empty() {
                         sg.child(build_generics_part_a(out, base_indent, &x.generics));
@@ -911,8 +933,9 @@ impl Formattable for Item {
                     }
                     append_comments(out, base_indent, &mut sg, x.trait_token.span.start());
                     prefix.push_str("trait ");
-                    prefix.push_str(&x.ident.to_string());
                     sg.seg(out, &prefix);
+                    append_comments(out, base_indent, &mut sg, x.ident.span().start());
+                    sg.seg(out, &x.ident.to_string());
                     if !x.generics.params.is_empty() {
                         sg.child(build_generics_part_a(out, base_indent, &x.generics));
                     }
@@ -954,9 +977,11 @@ impl Formattable for Item {
                     append_comments(out, base_indent, &mut sg, x.trait_token.span.start());
                     let mut prefix = String::new();
                     prefix.push_str("trait ");
-                    prefix.push_str(&x.ident.to_string());
                     sg.seg(out, &prefix);
+                    append_comments(out, base_indent, &mut sg, x.ident.span().start());
+                    sg.seg(out, &x.ident.to_string());
                     append_generics(out, base_indent, &mut sg, &x.generics);
+                    append_comments(out, base_indent, &mut sg, x.eq_token.span.start());
                     append_binary(out, base_indent, &mut sg, " =", |out: &mut MakeSegsState, base_indent: &Alignment| {
                         let mut node = new_sg(out);
                         append_inline_list(
@@ -984,9 +1009,11 @@ impl Formattable for Item {
                     append_comments(out, base_indent, &mut sg, x.type_token.span.start());
                     let mut prefix = String::new();
                     prefix.push_str("type ");
-                    prefix.push_str(&x.ident.to_string());
                     sg.seg(out, &prefix);
+                    append_comments(out, base_indent, &mut sg, x.ident.span().start());
+                    sg.seg(out, &x.ident.to_string());
                     append_generics(out, base_indent, &mut sg, &x.generics);
+                    append_comments(out, base_indent, &mut sg, x.eq_token.span.start());
                     append_binary(out, base_indent, &mut sg, " =", x.ty.as_ref());
                     append_comments(out, base_indent, &mut sg, x.semi_token.span.start());
                     sg.seg(out, ";");
@@ -1005,6 +1032,7 @@ impl Formattable for Item {
                     append_vis(out, base_indent, &mut sg, &x.vis);
                     append_comments(out, base_indent, &mut sg, x.union_token.span.start());
                     sg.seg(out, "union ");
+                    append_comments(out, base_indent, &mut sg, x.ident.span().start());
                     sg.seg(out, &x.ident.to_string());
                     append_generics(out, base_indent, &mut sg, &x.generics);
                     append_bracketed_list_curly(
@@ -1106,6 +1134,7 @@ impl Formattable for Variant {
                 syn::Fields::Unit => { },
             }
             if let Some(e) = &self.discriminant {
+                append_comments(out, base_indent, &mut sg, e.0.span.start());
                 append_binary(out, base_indent, &mut sg, " =", &e.1);
             }
             sg.build(out)
@@ -1124,7 +1153,11 @@ impl Formattable for Field {
             append_vis(out, base_indent, &mut sg, &self.vis);
             if let Some(n) = &self.ident {
                 append_comments(out, base_indent, &mut sg, n.span().start());
-                sg.seg(out, &format!("{}: ", n));
+                sg.seg(out, n);
+                if let Some(c) = &self.colon_token {
+                    append_comments(out, base_indent, &mut sg, c.span.start());
+                }
+                sg.seg(out, ": ");
             }
             sg.child(self.ty.make_segs(out, base_indent));
             sg.build(out)
@@ -1,6 +1,3 @@
-use std::{
-    fmt::Write,
-};
 use proc_macro2::LineColumn;
 use quote::ToTokens;
 use syn::{
@@ -63,6 +60,9 @@ pub(crate) fn build_extended_path(
                 },
                 n => {
                     node.child(qself.ty.make_segs(out, base_indent));
+                    if let Some(as_token) = &qself.as_token {
+                        append_comments(out, base_indent, &mut node, as_token.span.start());
+                    }
                     node.seg(out, " as ");
                     append_path(
                         out,
@@ -112,11 +112,11 @@ pub(crate) fn append_path<
     prefix: Option<Option<LineColumn>>,
     pairs: impl Iterator<Item = syn::punctuated::Pair<&'a syn::PathSegment, &'a syn::token::Colon2>>,
 ) {
-    let indent = base_indent.clone();
+    let indent = *base_indent;
     let mut prefix = prefix;
     for (i, seg) in pairs.enumerate() {
         if i > 0 {
-            node.split(out, indent.clone(), true);
+            node.split(out, indent, true);
         }
         if let Some(d) = prefix {
             if let Some(t) = d {
@@ -129,15 +129,16 @@ pub(crate) fn append_path<
         match &seg.value().arguments {
             syn::PathArguments::None => { },
             syn::PathArguments::AngleBracketed(a) => {
+                if let Some(colon2) = &a.colon2_token {
+                    append_comments(out, base_indent, node, colon2.spans[0].start());
+                    node.seg(out, "::");
+                }
                 node.child(
                     new_sg_bracketed_list_common(
                         out,
                         &indent,
                         a.lt_token.span.start(),
-                        &format!("{}{}", match &a.colon2_token {
-                            Some(_) => "::",
-                            None => "",
-                        }, "<"),
+                        "<",
                         &a.args,
                         a.gt_token.span.start(),
                         ">",
@@ -158,7 +159,8 @@ pub(crate) fn append_path<
                 );
                 match &a.output {
                     ReturnType::Default => { },
-                    ReturnType::Type(_, ty) => {
+                    ReturnType::Type(t, ty) => {
+                        append_comments(out, base_indent, node, t.spans[0].start());
                         node.seg(out, " -> ");
                         node.child(ty.make_segs(out, &indent));
                     },
@@ -176,13 +178,14 @@ pub(crate) fn build_ref(
     out: &mut MakeSegsState,
     base_indent: &Alignment,
     start: LineColumn,
-    mutability: bool,
+    mutability: Option<LineColumn>,
     expr: impl Formattable,
 ) -> SplitGroupIdx {
     let mut sg = new_sg(out);
     append_comments(out, base_indent, &mut sg, start);
     sg.seg(out, "&");
-    if mutability {
+    if let Some(mutability) = mutability {
+        append_comments(out, base_indent, &mut sg, mutability);
         sg.seg(out, "mut ");
     }
     sg.child(expr.make_segs(out, base_indent));
@@ -194,14 +197,18 @@ pub(crate) fn build_array_type(
     base_indent: &Alignment,
     start: LineColumn,
     expr: impl Formattable,
+    semi: LineColumn,
     len: &Expr,
+    end: LineColumn,
 ) -> SplitGroupIdx {
     let mut sg = new_sg(out);
     append_comments(out, base_indent, &mut sg, start);
     sg.seg(out, "[");
     sg.child(expr.make_segs(out, base_indent));
+    append_comments(out, base_indent, &mut sg, semi);
     sg.seg(out, "; ");
     sg.child(len.make_segs(out, base_indent));
+    append_comments(out, base_indent, &mut sg, end);
     sg.seg(out, "]");
     sg.build(out)
 }
@@ -256,7 +263,7 @@ pub(crate) fn build_generics_part_b(
     }
     sg.seg_unsplit(out, " ");
     append_comments(out, base_indent, &mut sg, wh.where_token.span.start());
-    sg.split(out, base_indent.clone(), true);
+    sg.split(out, *base_indent, true);
     sg.seg(out, "where");
     sg.seg_unsplit(out, " ");
 
@@ -285,6 +292,7 @@ impl Formattable for WherePredicate {
                     );
                 }
                 sg.child(t.bounded_ty.make_segs(out, base_indent));
+                append_comments(out, base_indent, &mut sg, t.colon_token.span.start());
                 sg.seg(out, ":");
                 sg.seg_unsplit(out, " ");
                 append_inline_list(out, base_indent, &mut sg, " +", &t.bounds, InlineListSuffix::<Expr>::None);
@@ -292,7 +300,9 @@ impl Formattable for WherePredicate {
             },
             WherePredicate::Lifetime(l) => {
                 let mut sg = new_sg(out);
+                append_comments(out, base_indent, &mut sg, l.lifetime.apostrophe.start());
                 sg.seg(out, &l.lifetime);
+                append_comments(out, base_indent, &mut sg, l.colon_token.span.start());
                 sg.seg(out, ":");
                 sg.seg_unsplit(out, " ");
                 append_inline_list(out, base_indent, &mut sg, " +", &l.bounds, InlineListSuffix::<Expr>::None);
@@ -324,7 +334,11 @@ impl Formattable for GenericParam {
                 |out: &mut MakeSegsState, base_indent: &Alignment| {
                     let build_base = |out: &mut MakeSegsState, base_indent: &Alignment| {
                         let mut sg = new_sg(out);
+                        append_comments(out, base_indent, &mut sg, t.ident.span().start());
                         sg.seg(out, &t.ident);
+                        if let Some(colon) = &t.colon_token {
+                            append_comments(out, base_indent, &mut sg, colon.span.start());
+                        }
                         if t.colon_token.is_some() && !t.bounds.is_empty() {
                             sg.seg(out, ": ");
                             append_inline_list(
@@ -352,12 +366,13 @@ impl Formattable for GenericParam {
                 &c.attrs,
                 |out: &mut MakeSegsState, base_indent: &Alignment| {
                     let build_base = |out: &mut MakeSegsState, base_indent: &Alignment| {
-                        let mut prefix = String::new();
-                        prefix.push_str("const ");
-                        prefix.push_str(&c.ident.to_string());
-                        prefix.push_str(": ");
                         let mut node = new_sg(out);
-                        node.seg(out, prefix);
+                        append_comments(out, base_indent, &mut node, c.const_token.span.start());
+                        node.seg(out, "const ");
+                        append_comments(out, base_indent, &mut node, c.ident.span().start());
+                        node.seg(out, &c.ident);
+                        append_comments(out, base_indent, &mut node, c.colon_token.span.start());
+                        node.seg(out, ": ");
                         node.child(c.ty.make_segs(out, base_indent));
                         node.build(out)
                     };
@@ -381,12 +396,16 @@ impl Formattable for TypeParamBound {
         let mut sg = new_sg(out);
         match self {
             syn::TypeParamBound::Trait(t) => {
-                if t.paren_token.is_some() {
+                if let Some(paren) = &t.paren_token {
+                    append_comments(out, base_indent, &mut sg, paren.span.start());
                     sg.seg(out, "(");
                 }
-                match t.modifier {
+                match &t.modifier {
                     syn::TraitBoundModifier::None => { },
-                    syn::TraitBoundModifier::Maybe(_) => sg.seg(out, "?"),
+                    syn::TraitBoundModifier::Maybe(q) => {
+                        append_comments(out, base_indent, &mut sg, q.span.start());
+                        sg.seg(out, "?");
+                    },
                 }
                 if let Some(hot) = &t.lifetimes {
                     append_comments(out, base_indent, &mut sg, hot.for_token.span.start());
@@ -409,11 +428,13 @@ impl Formattable for TypeParamBound {
                     t.path.leading_colon.map(|t| Some(t.spans[0].start())),
                     t.path.segments.pairs(),
                 );
-                if t.paren_token.is_some() {
+                if let Some(paren) = &t.paren_token {
+                    append_comments(out, base_indent, &mut sg, paren.span.end().prev());
                     sg.seg(out, ")");
                 }
             },
             syn::TypeParamBound::Lifetime(l) => {
+                append_comments(out, base_indent, &mut sg, l.apostrophe.start());
                 sg.seg(out, l.to_string());
             },
         }
@@ -429,8 +450,10 @@ impl Formattable for LifetimeDef {
     fn make_segs(&self, out: &mut MakeSegsState, base_indent: &Alignment) -> SplitGroupIdx {
         new_sg_outer_attrs(out, base_indent, &self.attrs, |out: &mut MakeSegsState, base_indent: &Alignment| {
             let mut node = new_sg(out);
+            append_comments(out, base_indent, &mut node, self.lifetime.apostrophe.start());
             node.seg(out, &self.lifetime);
-            if self.colon_token.is_some() {
+            if let Some(colon) = &self.colon_token {
+                append_comments(out, base_indent, &mut node, colon.span.start());
                 append_binary(out, base_indent, &mut node, ":", |out: &mut MakeSegsState, base_indent: &Alignment| {
                     let mut node = new_sg(out);
                     append_inline_list(
@@ -471,7 +494,9 @@ impl Formattable for &Type {
                 base_indent,
                 x.bracket_token.span.start(),
                 x.elem.as_ref(),
+                x.semi_token.span.start(),
                 &x.len,
+                x.bracket_token.span.end().prev(),
             ),
             Type::BareFn(x) => {
                 let mut sg = new_sg(out);
@@ -498,7 +523,7 @@ impl Formattable for &Type {
                     sg.seg(out, "extern ");
                     if let Some(name) = &abi.name {
                         append_comments(out, base_indent, &mut sg, name.span().start());
-                        sg.seg(out, name.to_token_stream().to_string());
+                        sg.seg(out, format!("{} ", name.to_token_stream()));
                     }
                 }
                 append_comments(out, base_indent, &mut sg, x.fn_token.span.start());
@@ -535,6 +560,7 @@ impl Formattable for &Type {
             Type::Group(x) => x.elem.make_segs(out, base_indent),
             Type::ImplTrait(x) => {
                 let mut node = new_sg(out);
+                append_comments(out, base_indent, &mut node, x.impl_token.span.start());
                 append_binary(out, base_indent, &mut node, "impl", |out: &mut MakeSegsState, base_indent: &Alignment| {
                     let mut node = new_sg(out);
                     append_inline_list(out, base_indent, &mut node, " +", &x.bounds, InlineListSuffix::<Expr>::None);
@@ -547,33 +573,39 @@ impl Formattable for &Type {
             Type::Never(x) => new_sg_lit(out, Some((base_indent, x.bang_token.span.start())), "!"),
             Type::Paren(x) => {
                 let mut node = new_sg(out);
+                append_comments(out, base_indent, &mut node, x.paren_token.span.start());
                 node.seg(out, "(");
                 node.child(x.elem.make_segs(out, base_indent));
+                append_comments(out, base_indent, &mut node, x.paren_token.span.end().prev());
                 node.seg(out, ")");
                 node.build(out)
             },
             Type::Path(x) => build_extended_path(out, base_indent, &x.qself, &x.path),
             Type::Ptr(x) => {
                 let mut node = new_sg(out);
-                let mut prefix = String::new();
-                prefix.write_str("*").unwrap();
-                if x.const_token.is_some() {
-                    prefix.write_str("const ").unwrap();
+                append_comments(out, base_indent, &mut node, x.star_token.span.start());
+                node.seg(out, "*");
+                if let Some(t) = &x.const_token {
+                    append_comments(out, base_indent, &mut node, t.span.start());
+                    node.seg(out, "const ");
                 }
-                if x.mutability.is_some() {
-                    prefix.write_str("mut ").unwrap();
+                if let Some(t) = &x.mutability {
+                    append_comments(out, base_indent, &mut node, t.span.start());
+                    node.seg(out, "mut ");
                 }
-                node.seg(out, prefix);
                 node.child(x.elem.make_segs(out, base_indent));
                 node.build(out)
             },
             Type::Reference(x) => {
                 let mut node = new_sg(out);
+                append_comments(out, base_indent, &mut node, x.and_token.span.start());
                 node.seg(out, "&");
                 if let Some(l) = &x.lifetime {
+                    append_comments(out, base_indent, &mut node, l.apostrophe.start());
                     node.seg(out, format!("{} ", l));
                 }
-                if x.mutability.is_some() {
+                if let Some(t) = &x.mutability {
+                    append_comments(out, base_indent, &mut node, t.span.start());
                     node.seg(out, "mut ");
                 }
                 node.child(x.elem.make_segs(out, base_indent));
@@ -581,13 +613,18 @@ impl Formattable for &Type {
             },
             Type::Slice(x) => {
                 let mut node = new_sg(out);
+                append_comments(out, base_indent, &mut node, x.bracket_token.span.start());
                 node.seg(out, "[");
                 node.child(x.elem.make_segs(out, base_indent));
+                append_comments(out, base_indent, &mut node, x.bracket_token.span.end().prev());
                 node.seg(out, "]");
                 node.build(out)
             },
             Type::TraitObject(x) => {
                 let mut node = new_sg(out);
+                if let Some(t) = &x.dyn_token {
+                    append_comments(out, base_indent, &mut node, t.span.start());
+                }
                 append_binary(out, base_indent, &mut node, "dyn", |out: &mut MakeSegsState, base_indent: &Alignment| {
                     let mut node = new_sg(out);
                     append_inline_list(out, base_indent, &mut node, " +", &x.bounds, InlineListSuffix::<Expr>::None);
@@ -607,7 +644,11 @@ impl Formattable for &Type {
                 x.paren_token.span.end().prev(),
                 ")",
             ),
-            Type::Verbatim(x) => new_sg_lit(out, None, x),
+            Type::Verbatim(x) => new_sg_lit(
+                out,
+                x.clone().into_iter().next().map(|t| (base_indent, t.span().start())),
+                x,
+            ),
             _ => unreachable!(),
         }
     }
@@ -632,7 +673,10 @@ impl Formattable for BareFnArg {
         new_sg_outer_attrs(out, base_indent, &self.attrs, |out: &mut MakeSegsState, base_indent: &Alignment| {
             if let Some(name) = &self.name {
                 let mut node = new_sg(out);
-                node.seg(out, format!("{}: ", name.0));
+                append_comments(out, base_indent, &mut node, name.0.span().start());
+                node.seg(out, &name.0);
+                append_comments(out, base_indent, &mut node, name.1.span.start());
+                node.seg(out, ": ");
                 node.child(self.ty.make_segs(out, base_indent));
                 node.build(out)
             } else {
@@ -718,8 +762,10 @@ impl Formattable for GenericArgument {
             ),
             GenericArgument::Constraint(c) => {
                 let mut node = new_sg(out);
+                append_comments(out, base_indent, &mut node, c.ident.span().start());
                 node.seg(out, &c.ident);
-                append_binary(out, base_indent, &mut node, " =", |out: &mut MakeSegsState, base_indent: &Alignment| {
+                append_comments(out, base_indent, &mut node, c.colon_token.span.start());
+                append_binary(out, base_indent, &mut node, ":", |out: &mut MakeSegsState, base_indent: &Alignment| {
                     let mut node = new_sg(out);
                     append_inline_list(out, base_indent, &mut node, " +", &c.bounds, InlineListSuffix::<Expr>::None);
                     node.build(out)
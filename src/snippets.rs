@@ -0,0 +1,259 @@
+//! Formatting Rust code embedded in markdown, like examples in doc comments.
+use anyhow::{
+    anyhow,
+    Result,
+};
+use markdown::mdast::{
+    Code,
+    Node,
+};
+use std::ops::Range;
+use syn::{
+    Block,
+    Expr,
+    File,
+    Item,
+    Stmt,
+    UseTree,
+};
+use crate::{
+    comments::md_parse_options,
+    format_str,
+    FormatConfig,
+};
+
+// Rustdoc attributes that can appear in a code block's info string without making
+// it non-Rust
+const RUSTDOC_ATTRS: &[&str] =
+    &["rust", "ignore", "should_panic", "no_run", "compile_fail", "test_harness", "allow_fail", "standalone_crate"];
+
+/// Whether a code block with this info string is Rust, using the same rules as
+/// rustdoc: no language, `rust`, or only rustdoc attributes like `no_run`.
+pub(crate) fn is_rust_block(info: &str) -> bool {
+    info
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|t| !t.is_empty())
+        .all(|t| RUSTDOC_ATTRS.contains(&t) || t.starts_with("edition") || t.starts_with("ignore-"))
+}
+
+/// Whether comment text looks like commented-out code. Short prose can parse too (`foo;`,
+/// `Note: x;`, `use this;`), so one statement or item on its own only counts if it does
+/// something: a `let`, an item other than `use` of a single name, or a call, assignment,
+/// control flow and the like. Several statements count unless they're all names or
+/// literals.
+pub(crate) fn is_commented_code(text: &str) -> bool {
+    let text = text.trim();
+    if text.is_empty() {
+        return false;
+    }
+    if let Ok(file) = syn::parse_str::<File>(text) {
+        match file.items.as_slice() {
+            [] => { },
+            [item] => if is_code_item(item) {
+                return true;
+            },
+            _ => return true,
+        }
+    }
+    let block = match syn::parse_str::<Block>(&format!("{{{}}}", text)) {
+        Ok(b) => b,
+        Err(_) => return false,
+    };
+    match block.stmts.as_slice() {
+        [] => false,
+        [Stmt::Local(_)] => true,
+        [Stmt::Item(item)] => is_code_item(item),
+        [Stmt::Semi(e, _)] | [Stmt::Expr(e)] => is_code_expr(e),
+        stmts => !stmts.iter().all(|stmt| match stmt {
+            Stmt::Semi(e, _) | Stmt::Expr(e) => matches!(e, Expr::Path(_) | Expr::Lit(_) | Expr::Type(_)),
+            _ => false,
+        }),
+    }
+}
+
+fn is_code_item(item: &Item) -> bool {
+    match item {
+        Item::Use(u) => !matches!(u.tree, UseTree::Name(_)),
+        Item::Verbatim(_) => false,
+        _ => true,
+    }
+}
+
+fn is_code_expr(e: &Expr) -> bool {
+    matches!(
+        e,
+        Expr::Assign(_) | Expr::AssignOp(_) | Expr::Call(_) | Expr::Closure(_) | Expr::ForLoop(_) | Expr::If(_) |
+            Expr::Loop(_) |
+            Expr::Macro(_) |
+            Expr::Match(_) |
+            Expr::MethodCall(_) |
+            Expr::Return(_) |
+            Expr::Struct(_) |
+            Expr::While(_)
+    )
+}
+
+/// Formats a code example, which can be a whole file or, like doctests, statements
+/// that get wrapped in `fn main`. Returns `None` if it couldn't be formatted cleanly.
+pub(crate) fn format_snippet(code: &str, config: &FormatConfig) -> Option<String> {
+    // Problems should leave the code as it was rather than be reported. Examples are read
+    // by people who don't know `//.`, so don't add it to commented-out code.
+    let config = FormatConfig {
+        quiet: true,
+        comment_errors_fatal: true,
+        detect_commented_code: false,
+        ..*config
+    };
+    if syn::parse_str::<File>(code).is_ok() {
+        let res = format_str(code, &config).ok()?;
+        if !res.lost_comments.is_empty() || syn::parse_str::<File>(&res.rendered).is_err() {
+            return None;
+        }
+        return Some(res.rendered.trim_end().to_string());
+    }
+
+    // Indent everything (including multi-line string contents) so removing the indentation
+    // afterwards leaves literals as they were
+    let mut wrapped = "fn main() {\n".to_string();
+    for line in code.lines() {
+        if !line.is_empty() {
+            wrapped.push_str("    ");
+        }
+        wrapped.push_str(line);
+        wrapped.push('\n');
+    }
+    wrapped.push_str("}\n");
+    let res = format_str(&wrapped, &config).ok()?;
+    if !res.lost_comments.is_empty() {
+        return None;
+    }
+    let body = res.rendered.strip_prefix("fn main() {")?.trim_end().strip_suffix('}')?;
+    if syn::parse_str::<Block>(&format!("{{{}}}", body)).is_err() {
+        return None;
+    }
+    if !body.starts_with('\n') {
+        // Fit on one line, `fn main() { ... }`
+        return Some(body.trim().to_string());
+    }
+    let mut out = vec![];
+    for line in body.trim_matches('\n').lines() {
+        if line.is_empty() {
+            out.push(line);
+        } else {
+            out.push(line.strip_prefix("    ")?);
+        }
+    }
+    Some(out.join("\n"))
+}
+
+/// Formats a rustdoc example. Hidden lines (starting with `# `) at the start and end
+/// are kept as they are and the lines between are formatted; examples with hidden
+/// lines in the middle aren't formatted.
+pub(crate) fn format_doc_example(code: &str, config: &FormatConfig) -> Option<String> {
+    fn is_hidden(line: &str) -> bool {
+        let line = line.trim_start();
+        line == "#" || line.starts_with("# ")
+    }
+
+    let lines = code.lines().collect::<Vec<&str>>();
+    let start = lines.iter().position(|l| !is_hidden(l))?;
+    let end = lines.iter().rposition(|l| !is_hidden(l))? + 1;
+    if lines[start .. end].iter().any(|l| is_hidden(l)) {
+        return None;
+    }
+    let formatted = format_snippet(&lines[start .. end].join("\n"), config)?;
+    let mut out = lines[..start].to_vec();
+    out.push(&formatted);
+    out.extend(&lines[end..]);
+    Some(out.join("\n"))
+}
+
+/// Formats the Rust code blocks in a markdown document like a readme, leaving
+/// everything else exactly as it was. Blocks that can't be formatted are left alone.
+pub fn format_md_code_blocks(source: &str, config: &FormatConfig) -> Result<String> {
+    fn collect<'a>(node: &'a Node, blocks: &mut Vec<&'a Code>) {
+        let children = match node {
+            Node::Code(x) => {
+                blocks.push(x);
+                return;
+            },
+            Node::Root(x) => &x.children,
+            Node::BlockQuote(x) => &x.children,
+            Node::List(x) => &x.children,
+            Node::ListItem(x) => &x.children,
+            Node::FootnoteDefinition(x) => &x.children,
+            _ => return,
+        };
+        for child in children {
+            collect(child, blocks);
+        }
+    }
+
+    let ast = markdown::to_mdast(source, &md_parse_options()).map_err(|e| anyhow!("{}", e))?;
+    let mut blocks = vec![];
+    collect(&ast, &mut blocks);
+    let mut out = String::new();
+    let mut at = 0;
+    for block in blocks {
+        if let Some((range, replacement)) = format_md_code_block(source, block, config) {
+            out.push_str(&source[at .. range.start]);
+            out.push_str(&replacement);
+            at = range.end;
+        }
+    }
+    out.push_str(&source[at..]);
+    Ok(out)
+}
+
+/// The range of the lines inside a fenced Rust code block, and the formatted lines to
+/// replace them with if they changed.
+fn format_md_code_block(source: &str, block: &Code, config: &FormatConfig) -> Option<(Range<usize>, String)> {
+    // Unlike in doc comments, blocks without a language are usually something else
+    let info = format!("{} {}", block.lang.as_deref().unwrap_or(""), block.meta.as_deref().unwrap_or(""));
+    if !is_rust_block(&info) || !info.split(|c: char| c == ',' || c.is_whitespace()).any(|t| t == "rust") {
+        return None;
+    }
+    let position = block.position.as_ref()?;
+    let text = source.get(position.start.offset .. position.end.offset)?;
+    if text.contains('\r') {
+        return None;
+    }
+    let fence_text = text.trim_start_matches(' ');
+    let fence = fence_text.get(..3).filter(|f| *f == "```" || *f == "~~~")?;
+
+    // Skip unclosed blocks, the end isn't clear
+    let last_line_start = text.rfind('\n')? + 1;
+    if !text[last_line_start..].trim_start_matches(|c: char| c == '>' || c.is_whitespace()).starts_with(fence) {
+        return None;
+    }
+    let content = position.start.offset + text.find('\n')? + 1 .. position.start.offset + last_line_start - 1;
+    if content.start >= content.end {
+        return None;
+    }
+
+    // Continuation lines of lists and block quotes need the same indentation and `>`s as
+    // the line with the opening fence
+    let line_start = source[..position.start.offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let prefix =
+        source[line_start .. position.start.offset + text.len() - fence_text.len()]
+            .chars()
+            .map(|c| if c == '>' || c.is_whitespace() {
+                c
+            } else {
+                ' '
+            })
+            .collect::<String>();
+    let formatted = format_doc_example(&block.value, &FormatConfig {
+        max_width: config.max_width.saturating_sub(prefix.chars().count()),
+        ..*config
+    })?;
+    if formatted == block.value {
+        return None;
+    }
+    let lines = formatted.lines().map(|l| if l.is_empty() {
+        prefix.trim_end().to_string()
+    } else {
+        format!("{}{}", prefix, l)
+    }).collect::<Vec<String>>();
+    Some((content, lines.join("\n")))
+}
@@ -0,0 +1,31 @@
+struct A<'a, T: Clone + 'a, U = u8, const N: usize = 3> where T: Send, U: Sync {
+    a: &'a T,
+}
+
+impl<'a, T> Trait for A<'a, T> where T: Iterator<Item = u8> + ?Sized, for<'b> &'b T: Into<u8>, 'a: 'static, {
+    type Item = &'static T;
+
+    fn f<V: Default>(self, v: V) -> V where V: Clone {
+        v
+    }
+}
+
+trait Trait<T>: Sized + Send where Self: Clone {
+    type Item: Clone;
+    const C: u8 = 1;
+
+    fn f<V: Default>(self, v: V) -> V where V: Clone;
+}
+
+fn f<T>(x: T)
+where
+    T: Fn(u8) -> u8 {
+    x(1);
+}
+
+fn g<'a, 'b, 'c>(x: &'a u8, y: &'b u8) -> &'c u8
+where
+    'a: 'b + 'c,
+    'b: 'c {
+    x
+}
@@ -0,0 +1,31 @@
+fn f((a, b): (u8, u8), Point { x, y: _ }: Point, [first, .., last]: [u8; 4], &mut c: &mut u8) { }
+
+fn g(x: Thing) {
+    let (a, ref mut b, ..) = x;
+    let Thing(c, _, ..) = x;
+    let Thing { d, e: ref f, .. } = x;
+    let g @ 1 ..= 5 = 3;
+    let h: u8 = 1;
+    let [i, j @ .., k] = x;
+    let &(l, m) = x;
+    let crate::Thing::Variant = x;
+    let <T as Trait>::Const = x;
+    match x {
+        1 | 2 => { },
+        3 ..= 5 => { },
+        -1 => { },
+        "s" => { },
+        Some(n) if n > 1 => { },
+        Enum::A { .. } => { },
+        m!(x) => { },
+        box o => { },
+        _ => { },
+    }
+    if let Some(p) | None = x { }
+    while let Ok(q) = x { }
+    for (r, s) in x { }
+    let t = |u: u8, (v, w): (u8, u8), mut x| u;
+    let y = move |&(a, b): &(u8, u8), _| -> u8 { a };
+    let z = async move |Thing { c, .. }| c;
+    let w = || 1;
+}
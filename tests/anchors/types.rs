@@ -0,0 +1,30 @@
+type A = u8;
+type B = std::collections::HashMap<String, Vec<u8>>;
+type C = &'static mut [u8];
+type D = (u8, i32, ());
+type E = [u8; 4];
+type F = fn(u8, b: i32) -> bool;
+type G = unsafe extern "C" fn(*const u8, ...);
+type H = Box<dyn Fn(u8) -> u8 + Send + 'static>;
+type I = <T as Iterator>::Item;
+type J = ::std::vec::Vec<u8>;
+type K = *mut u8;
+type L = for<'a> fn(&'a u8);
+type M = m!(u8);
+type N = Option<!>;
+type O = Vec<_>;
+type P = (u8,);
+type Q = Box<dyn ?Sized + Send>;
+type R = (u8);
+type S = impl Iterator<Item = u8>;
+type T = Foo<'a, N, 3, { 1 + 2 }>;
+type U = Self::Thing;
+type X = unsafe extern "C" fn(a: u8, b: ...);
+
+fn f(x: impl Into<u8>) -> impl Iterator<Item = &'static u8> { }
+
+fn g() {
+    let x: Vec<u8> = Vec::<u8>::new();
+    let y = x.into_iter().collect::<Vec<_>>();
+    let z = <Vec<u8> as Default>::default();
+}
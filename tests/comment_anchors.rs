@@ -0,0 +1,155 @@
+#![cfg(test)]
+
+use genemichaels::{
+    extract_comments,
+    format_str,
+    FormatConfig,
+    HashLineColumn,
+};
+use proc_macro2::{
+    Delimiter,
+    LineColumn,
+    Spacing,
+    TokenStream,
+    TokenTree,
+};
+use std::str::FromStr;
+
+fn to_offset(text: &str, loc: LineColumn) -> usize {
+    let line_start = text.split_inclusive('\n').take(loc.line - 1).map(str::len).sum::<usize>();
+    line_start + text[line_start..].chars().take(loc.column).map(char::len_utf8).sum::<usize>()
+}
+
+/// Puts a numbered inline comment before every token in `text`, except within
+/// multi-character punctuation.
+fn comment_everywhere(text: &str) -> String {
+    fn recurse(locs: &mut Vec<LineColumn>, ts: TokenStream) {
+        let mut joint = false;
+        for t in ts {
+            if !joint {
+                locs.push(t.span().start());
+            }
+            joint = false;
+            match t {
+                TokenTree::Group(g) => {
+                    recurse(locs, g.stream());
+                    locs.push(g.span_close().start());
+                },
+                TokenTree::Punct(p) => {
+                    joint = p.spacing() == Spacing::Joint;
+                },
+                _ => { },
+            }
+        }
+    }
+
+    let mut locs = vec![];
+    recurse(&mut locs, TokenStream::from_str(text).unwrap());
+    let mut out = text.to_string();
+    for (i, loc) in locs.iter().enumerate().rev() {
+        out.insert_str(to_offset(text, *loc), &format!("/* {} */ ", i));
+    }
+    out
+}
+
+/// The tokens in `text` with comments before the token they're attached to, minus
+/// commas since those come and go depending on how lists are split.
+fn tokens_and_comments(text: &str) -> Vec<String> {
+    fn add(
+        out: &mut Vec<String>,
+        comments: &mut std::collections::HashMap<HashLineColumn, Vec<genemichaels::Comment>>,
+        loc: LineColumn,
+    ) {
+        for c in comments.remove(&HashLineColumn(loc)).unwrap_or_default() {
+            out.push(format!("/* {} */", c.lines));
+        }
+    }
+
+    fn recurse(
+        out: &mut Vec<String>,
+        comments: &mut std::collections::HashMap<HashLineColumn, Vec<genemichaels::Comment>>,
+        ts: TokenStream,
+    ) {
+        for t in ts {
+            match t {
+                TokenTree::Group(g) => {
+                    let (open, close) = match g.delimiter() {
+                        Delimiter::Parenthesis => ("(", ")"),
+                        Delimiter::Brace => ("{", "}"),
+                        Delimiter::Bracket => ("[", "]"),
+                        Delimiter::None => ("", ""),
+                    };
+                    add(out, comments, g.span_open().start());
+                    out.push(open.to_string());
+                    recurse(out, comments, g.stream());
+                    add(out, comments, g.span_close().start());
+                    out.push(close.to_string());
+                },
+                t => {
+                    add(out, comments, t.span().start());
+                    let text = t.to_string();
+                    if text != "," {
+                        out.push(text);
+                    }
+                },
+            }
+        }
+    }
+
+    let (mut comments, tokens) = extract_comments(text).unwrap();
+    let mut out = vec![];
+    recurse(&mut out, &mut comments, tokens);
+    add(&mut out, &mut comments, LineColumn {
+        line: 0,
+        column: 1,
+    });
+    out
+}
+
+fn t(text: &str) {
+    let text = comment_everywhere(text);
+    let config = FormatConfig {
+        quiet: true,
+        ..Default::default()
+    };
+    let res = format_str(&text, &config).unwrap();
+    assert!(res.lost_comments.is_empty(), "Comments lost: {:?}", res.lost_comments);
+    let want = tokens_and_comments(&text);
+    let got = tokens_and_comments(&res.rendered);
+    if let Some(i) = want.iter().zip(&got).position(|(w, g)| w != g).or_else(|| if want.len() != got.len() {
+        Some(want.len().min(got.len()))
+    } else {
+        None
+    }) {
+        let context = |v: &Vec<String>| v[i.saturating_sub(8) .. (i + 8).min(v.len())].join(" ");
+        panic!(
+            "Comments moved at token {}:\n\nExpected:\n{}\n\nGot:\n{}\n\nFormatted:\n{}\n",
+            i,
+            context(&want),
+            context(&got),
+            res.rendered
+        );
+    }
+    let res2 = format_str(&res.rendered, &config).unwrap();
+    assert!(
+        res.rendered == res2.rendered,
+        "Formatting isn't stable:\n\nFirst:\n{}\n\nSecond:\n{}\n",
+        res.rendered,
+        res2.rendered
+    );
+}
+
+#[test]
+fn anchors_types() {
+    t(include_str!("anchors/types.rs"));
+}
+
+#[test]
+fn anchors_patterns() {
+    t(include_str!("anchors/patterns.rs"));
+}
+
+#[test]
+fn anchors_generics() {
+    t(include_str!("anchors/generics.rs"));
+}
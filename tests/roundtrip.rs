@@ -1,17 +1,35 @@
 #![cfg(test)]
 
 use genemichaels::{
+    CommentStyle,
+    format_md_code_blocks,
     format_str,
     FormatConfig,
 };
 
 fn rt(text: &str) {
-    let res = format_str(text, &FormatConfig {
+    rt_with(text, FormatConfig {
         max_width: 120,
+        debug_checks: true,
         ..Default::default()
-    }).unwrap();
-    assert!(res.lost_comments.is_empty(), "Comments remain: {:?}", res.lost_comments);
-    assert!(text == res.rendered, "Formatted text changed:\n\nBefore:\n{}\n\nAfter:\n{}\n", text, res.rendered);
+    });
+}
+
+fn rt_with(text: &str, config: FormatConfig) {
+    for item_by_item in [false, true] {
+        let res = format_str(text, &FormatConfig {
+            item_by_item,
+            ..config
+        }).unwrap();
+        assert!(res.lost_comments.is_empty(), "Comments remain: {:?}", res.lost_comments);
+        assert!(
+            text == res.rendered,
+            "Formatted text changed (item by item {}):\n\nBefore:\n{}\n\nAfter:\n{}\n",
+            item_by_item,
+            text,
+            res.rendered
+        );
+    }
 }
 
 #[test]
@@ -178,6 +196,500 @@ fn rt_comments_verbatim1() {
 "#);
 }
 
+#[test]
+fn rt_comments_trailing1() {
+    rt_with(
+        r#"const A: i32 = 1; // one
+const B: i32 = 2; //~ ERROR two
+
+fn main() {
+    // before
+    let x = 5; // seconds
+    foo(
+        1, // first
+        2,
+    );
+    if x { // open
+        y
+    } // close
+}
+"#,
+        FormatConfig {
+            max_width: 120,
+            debug_checks: true,
+            keep_trailing_comments: true,
+            ..Default::default()
+        },
+    );
+}
+
+#[test]
+fn comments_adjacent1() {
+    // Both become line comments, which would be merged into one paragraph next time
+    // without a separator
+    let res = format_str(r#"fn main() {
+    /* a */
+    // b
+    x();
+}
+"#, &FormatConfig::default()).unwrap();
+    assert_eq!(res.rendered, r#"fn main() {
+    // a
+    //
+    // b
+    x();
+}
+"#);
+    rt(&res.rendered);
+}
+
+#[test]
+fn comments_trailing_long1() {
+    let res =
+        format_str(
+            r#"fn main() {
+    let x = 5; // seconds, and this comment is long enough to not fit
+}
+"#,
+            &FormatConfig {
+                max_width: 40,
+                keep_trailing_comments: true,
+                ..Default::default()
+            },
+        ).unwrap();
+    assert_eq!(
+        res.rendered,
+        r#"fn main() {
+    // seconds, and this comment is long enough to not fit
+    let x = 5;
+}
+"#
+    );
+}
+
+#[test]
+fn rt_comments_inline1() {
+    rt(
+        r#"fn main() {
+    foo(/* verbose */ true, /* retries */ 3);
+    bar(/* nothing */);
+    let x: Vec</* item */ u8> = vec![a /* last */];
+    match x {
+        _ => { /* nothing */ },
+    }
+    baz(
+        1,
+        // two
+    );
+}
+"#,
+    );
+}
+
+#[test]
+fn comments_line_end_block1() {
+    // Block comments after the last token on a line are moved like line comments
+    let text = r#"fn main() {
+    a(); /* x */
+    b(); /* y */ // z
+    c();
+}
+"#;
+    let res = format_str(text, &FormatConfig::default()).unwrap();
+    assert!(res.lost_comments.is_empty(), "Comments remain: {:?}", res.lost_comments);
+    assert_eq!(res.rendered, r#"fn main() {
+    // x
+    a();
+
+    // y
+    //
+    // z
+    b();
+    c();
+}
+"#);
+    let res = format_str(text, &FormatConfig {
+        keep_trailing_comments: true,
+        ..Default::default()
+    }).unwrap();
+    assert_eq!(res.rendered, r#"fn main() {
+    a(); // x
+    b(); // y
+    // z
+    c();
+}
+"#);
+}
+
+#[test]
+fn rt_comments_verbatim_tokens1() {
+    // Types and patterns syn can't parse are written as-is, with comments before them kept
+    rt(
+        r#"type V = /* a */ dyn * Send;
+type W = fn (mut self);
+type X = unsafe extern "C" fn(a: u8, b: /* b */...);
+
+fn main() {
+    match x {
+        /* c */..= 9 => { },
+        // d
+        const { 1 + 2 } => { },
+    }
+}
+"#,
+    );
+}
+
+#[test]
+fn rt_comments_inline_split1() {
+    // Inline comments before the first token of a line start it rather than ending the
+    // previous one, except before a closing bracket
+    rt(
+        r#"fn main() {
+    foo(
+        aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa,
+        bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb,
+        cccccccccccccc, /* end */
+    );
+    let x =
+        aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa
+            /* a */.bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb()
+            /* b */.ccccccccccccccccccccccccccccccccc();
+}
+"#,
+    );
+}
+
+#[test]
+fn rt_comments_style_preserve1() {
+    rt_with(
+        r#"/*
+ * License header that is long enough that it won't fit on a single line with the
+ * comment markers.
+ *
+ * Second paragraph.
+ */
+/** Block doc */
+struct A;
+
+fn main() {
+    /* block */
+    x();
+
+    // line
+    y();
+}
+"#,
+        FormatConfig {
+            max_width: 120,
+            debug_checks: true,
+            comment_style: None,
+            ..Default::default()
+        },
+    );
+}
+
+#[test]
+fn comments_style_block1() {
+    let res =
+        format_str(
+            r#"// a
+/// doc
+fn main() {
+    // contains */ so stays a line comment
+    x();
+}
+"#,
+            &FormatConfig {
+                comment_style: Some(CommentStyle::Block),
+                ..Default::default()
+            },
+        ).unwrap();
+    assert_eq!(
+        res.rendered,
+        r#"/* a */
+/** doc */
+fn main() {
+    // contains */ so stays a line comment
+    x();
+}
+"#
+    );
+}
+
+#[test]
+fn comments_commented_code1() {
+    let text =
+        r#"fn main() {
+    // let x = vec![1,
+    //     2];
+    x();
+
+    // Prose with a call(in) it
+    y();
+}
+"#;
+    let res = format_str(text, &FormatConfig {
+        detect_commented_code: true,
+        ..Default::default()
+    }).unwrap();
+    assert_eq!(
+        res.rendered,
+        r#"fn main() {
+    //. let x = vec![1,
+    //.     2];
+    x();
+
+    // Prose with a call(in) it
+    y();
+}
+"#
+    );
+    rt(&res.rendered);
+    let res = format_str(text, &FormatConfig::default()).unwrap();
+    assert!(!res.rendered.contains("//."), "Comment made verbatim:\n{}", res.rendered);
+}
+
+#[test]
+fn comments_commented_code_prose1() {
+    let text =
+        r#"fn main() {
+    // Note: x;
+    x();
+
+    // done;
+    y();
+
+    // use this;
+    z();
+
+    // a - b;
+    w();
+}
+"#;
+    let res = format_str(text, &FormatConfig {
+        detect_commented_code: true,
+        ..Default::default()
+    }).unwrap();
+    assert_eq!(res.rendered, text);
+}
+
+#[test]
+fn comments_commented_code_block1() {
+    let config = FormatConfig {
+        comment_style: None,
+        detect_commented_code: true,
+        ..Default::default()
+    };
+    let text =
+        r#"fn main() {
+    /* if a {
+        b();
+    } */
+    x();
+}
+"#;
+    let res = format_str(text, &config).unwrap();
+    assert_eq!(
+        res.rendered,
+        r#"fn main() {
+    /*
+       if a {
+           b();
+       }
+     */
+    x();
+}
+"#
+    );
+    assert_eq!(format_str(&res.rendered, &config).unwrap().rendered, res.rendered);
+}
+
+#[test]
+fn rt_comments_table1() {
+    rt(
+        r#"/// | Name        | Value | Notes  |
+/// | :---------- | ----: | :----: |
+/// | `a\|b`      |     1 | middle |
+/// | longer name |    22 |        |
+fn main() { }
+"#,
+    );
+}
+
+#[test]
+fn rt_comments_table_wide1() {
+    rt(
+        r#"/// | this | table | is | much | too | wide | to | fit | anywhere | sensible | on | a | single | line | at | all | even with a max width of one hundred and twenty |
+/// |---|---|---|---|---|---|---|---|---|---|---|---|---|---|---|---|---|
+/// | a | b |
+fn main() { }
+"#,
+    );
+}
+
+#[test]
+fn rt_comments_table_wide2() {
+    // Fits in 40 columns only without the `/// ` prefix
+    rt_with(r#"/// | first | second | third |
+/// |---|---|---|
+/// | aaaaaaaaaaaaaaaaa | b | c |
+fn main() { }
+"#, FormatConfig {
+        max_width: 40,
+        debug_checks: true,
+        comment_width: None,
+        ..Default::default()
+    });
+}
+
+#[test]
+fn rt_comments_table_unicode1() {
+    rt(r#"/// | 名前 | Value |
+/// | ---- | ----- |
+/// | é    | 1     |
+/// | 🦀   | 22    |
+fn main() { }
+"#);
+}
+
+#[test]
+fn rt_comments_gfm1() {
+    rt(
+        r#"/// * [ ] not done
+///
+/// * [x] done ~~struck~~ and ~also struck~
+///
+/// See https://example.com/a?b=1, www.example.org or me@example.com and
+/// <https://example.com/b>, with a line break before `code`.
+///
+/// Footnote[^1] here.
+///
+/// [^1]: The note.
+///
+///     Second paragraph.
+fn main() { }
+"#,
+    );
+}
+
+#[test]
+fn rt_comments_gfm_attached1() {
+    rt(
+        r#"/// Contrary to popular belief, it is ok and often preferred to use an ordinary mutex
+/// (~~`Mutex`~~), which comes from the standard library and is explained further in
+/// the footnote[^mutex].
+///
+/// [^mutex]: A footnote long enough that it has to be wrapped onto a second line, which is indented
+///     to line up with the paragraphs after it.
+///
+///     Second paragraph.
+fn main() { }
+"#,
+    );
+}
+
+#[test]
+fn rt_comments_code1() {
+    rt(
+        r#"/// ```
+/// # use std::collections::HashMap;
+/// let mut m = HashMap::new();
+/// m.insert(1, "a");
+/// ```
+///
+/// ```text
+/// let   a=1;
+/// ```
+///
+/// ```
+/// not   rust ((
+/// ```
+///
+/// ```no_run
+/// # fn main() {
+/// let   x=1;
+/// # let y = 2;
+/// let   z=3;
+/// # }
+/// ```
+fn main() { }
+"#,
+    );
+}
+
+#[test]
+fn comments_code_format1() {
+    let res =
+        format_str(
+            r#"/// ```rust,no_run
+/// # fn main() {
+/// let x=vec![1,2];
+/// # }
+/// ```
+fn main() { }
+"#,
+            &FormatConfig::default(),
+        ).unwrap();
+    assert_eq!(
+        res.rendered,
+        r#"/// ```rust,no_run
+/// # fn main() {
+/// let x = vec![1, 2];
+/// # }
+/// ```
+fn main() { }
+"#
+    );
+}
+
+#[test]
+fn md_code_blocks1() {
+    let source =
+        r#"# Title
+
+Some   *text*   as is.
+
+```rust
+let x=vec![1,2];
+```
+
+```
+let   y=1;
+```
+
+* Item
+
+  ```rust,no_run
+  fn   f(){ g( ) }
+  ```
+"#;
+    let res = format_md_code_blocks(source, &FormatConfig::default()).unwrap();
+    assert_eq!(
+        res,
+        r#"# Title
+
+Some   *text*   as is.
+
+```rust
+let x = vec![1, 2];
+```
+
+```
+let   y=1;
+```
+
+* Item
+
+  ```rust,no_run
+  fn f() {
+      g()
+  }
+  ```
+"#
+    );
+}
+
 #[test]
 fn rt_try_try1() {
     rt(r#"fn main() {
@@ -238,6 +750,18 @@ fn rt_trait2() {
 "#)
 }
 
+#[test]
+fn rt_type_assoc_constraint1() {
+    rt(r#"fn main<T: Iterator<Item: Clone + Send>>() { }
+"#);
+}
+
+#[test]
+fn rt_type_bare_fn_abi1() {
+    rt(r#"fn main(f: extern "C" fn(i32) -> i32) { }
+"#);
+}
+
 #[test]
 fn rt_fn1() {
     rt(r#"fn main<T>()
@@ -245,3 +769,70 @@ where
     T: Fn() { }
 "#);
 }
+
+#[test]
+fn rt_items_inner_attrs1() {
+    rt(
+        r#"#![allow(dead_code)]
+#![allow(unused)]
+
+// Item comment
+use std::fmt;
+use std::io;
+
+fn main() { }
+
+const X: i32 = 7;
+// This is where the file ends.
+"#,
+    );
+}
+
+#[test]
+fn rt_comments_reattach1() {
+    rt(r#"// a
+mod
+// b
+m {
+    fn f() { }
+}
+"#);
+}
+
+#[test]
+fn comments_after_separator1() {
+    let res = format_str(r#"fn f() {
+    let x =
+    // c
+    1;
+}
+"#, &FormatConfig::default()).unwrap();
+    assert_eq!(res.rendered, r#"fn f() {
+    let x =
+        // c
+        1;
+}
+"#);
+}
+
+#[test]
+fn comments_reattach2() {
+    let res = format_str(r#"// a
+mod m {
+    extern
+    // b
+    crate c;
+}
+"#, &FormatConfig::default()).unwrap();
+    assert!(res.lost_comments.is_empty(), "Comments remain: {:?}", res.lost_comments);
+    assert_eq!(res.rendered, r#"// a
+mod m {
+    // b
+    extern crate c;
+}
+"#);
+    assert_eq!(
+        res.moved_comments.iter().map(|m| m.to_string()).collect::<Vec<_>>(),
+        vec!["Comments before 5:4 can't be placed there, moved them to before 3:4"]
+    );
+}
@@ -0,0 +1,14 @@
+use genemichaels::{
+    FormatConfig,
+    FormatRes,
+    MakeSegsState,
+};
+
+fn assert_send<T: Send>() { }
+
+#[test]
+fn send_state() {
+    assert_send::<MakeSegsState>();
+    assert_send::<FormatConfig>();
+    assert_send::<FormatRes>();
+}